@@ -0,0 +1,76 @@
+use provme::{
+    deprovision, path_string, DeprovisionRequest, Error, Layout, LayoutOptions, SystemRunner,
+};
+use std::path::PathBuf;
+use std::process;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+#[structopt(name = "rmwebuser")]
+struct Opt {
+    #[structopt(short, long, parse(from_os_str))]
+    base: Option<PathBuf>,
+
+    #[structopt(short, long)]
+    username: String,
+
+    #[structopt(short, long, parse(from_os_str))]
+    mountbase: Option<PathBuf>,
+
+    #[structopt(long, parse(from_os_str))]
+    sshd_config_dir: Option<PathBuf>,
+
+    #[structopt(long, parse(from_os_str))]
+    inventory: Option<PathBuf>,
+
+    /// Directory holding the images of the `image` backend
+    #[structopt(long, parse(from_os_str))]
+    image_store: Option<PathBuf>,
+
+    /// Volume group for accounts of the `lvm` backend [default: provme]
+    #[structopt(long)]
+    volume_group: Option<String>,
+
+    /// Directory holding the authorized_keys files, outside of the chroots
+    #[structopt(long, parse(from_os_str))]
+    authorized_keys_dir: Option<PathBuf>,
+
+    /// Move the image into this directory instead of deleting it
+    #[structopt(short, long, parse(from_os_str))]
+    archive: Option<PathBuf>,
+}
+
+fn main() {
+    if let Err(err) = run() {
+        eprintln!("[ERROR] {}", err);
+        process::exit(err.exit_code());
+    }
+}
+
+fn run() -> Result<(), Error> {
+    // Parse arguments
+    let opt: Opt = Opt::from_args();
+    let request = DeprovisionRequest {
+        username: opt.username.clone(),
+        layout: Layout::from_options(&LayoutOptions {
+            base_directory: opt.base.clone(),
+            mount_base: opt.mountbase.clone(),
+            sshd_config_dir: opt.sshd_config_dir.clone(),
+            inventory_path: opt.inventory.clone(),
+            image_store: opt.image_store.clone(),
+            volume_group: opt.volume_group.clone(),
+            authorized_keys_dir: opt.authorized_keys_dir.clone(),
+        })?,
+        archive_directory: opt.archive.as_deref().map(path_string).transpose()?,
+    };
+
+    // Delete web space account
+    deprovision(&SystemRunner, &request)?;
+
+    println!(
+        "[SUCCESS] User {{ name: {user} }} deleted",
+        user = opt.username
+    );
+
+    Ok(())
+}
@@ -1,5 +1,10 @@
+use provme::{
+    load_ssh_keys, path_string, provision, Allocation, Backend, DryRunRunner, Error, Filesystem,
+    FormatOptions, Layout, LayoutOptions, Password, ProvisionRequest, Runner, SshKey, SystemRunner,
+};
+use std::io;
 use std::path::PathBuf;
-use std::process::{Command, ExitStatus, Stdio};
+use std::process;
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
@@ -13,219 +18,153 @@ struct Opt {
 
     #[structopt(short, long)]
     quota: Option<u64>,
-}
 
-#[derive(Debug)]
-enum AppError {
-    UserCreationFailed { reason: &'static str },
-    UserSpaceCreationFailed { reason: &'static str },
-    UserSpaceFormattingFailed { reason: &'static str },
-}
+    /// Image allocation: `preallocated` or `sparse` (thin-provisioned)
+    #[structopt(long, default_value = "preallocated")]
+    allocation: Allocation,
 
-#[derive(Debug)]
-struct User {
-    username: String,
-    base_directory: String,
-    home_directory: String,
-}
+    /// Storage backend: `image` (loop-mounted image), `quota` (kernel user quota),
+    /// `xfs` (XFS project quota), `btrfs` (subvolume with qgroup limit) or `lvm` (logical volume)
+    #[structopt(long, default_value = "image")]
+    backend: Backend,
 
-#[derive(Debug)]
-struct UserSpace {
-    name: String,
-    path: String,
-    size_mb: u64,
-}
+    /// Filesystem of image and LVM spaces: `ext4`, `xfs` or `btrfs`
+    #[structopt(long, default_value = "ext4")]
+    filesystem: Filesystem,
 
-#[derive(Debug)]
-struct WebSpaceAccount {
-    user: User,
-    userspace: UserSpace,
-}
+    /// Percentage of blocks reserved for root (ext4 only)
+    #[structopt(long)]
+    reserved_blocks: Option<u8>,
 
-fn main() -> Result<(), AppError> {
-    // Parse arguments
-    let opt: Opt = Opt::from_args();
+    /// Bytes per inode (ext4 only)
+    #[structopt(long, conflicts_with = "inodes")]
+    inode_ratio: Option<u64>,
 
-    // Create web space account
-    let acc = {
-        // Create user
-        let user = create_user(&opt)?;
+    /// Number of inodes (ext4 only)
+    #[structopt(long)]
+    inodes: Option<u64>,
 
-        // Create user space with quota
-        let userspace = create_user_space(&opt, &user)?;
+    /// Volume label, e.g. the username
+    #[structopt(long)]
+    label: Option<String>,
 
-        // Instantiate data structure
-        WebSpaceAccount { user, userspace }
-    };
+    /// Comma-separated ext4 features to enable, or disable with `^` (ext4 only)
+    #[structopt(long)]
+    ext4_features: Option<String>,
 
-    println!(
-        "[SUCCESS] User {{ name: {user} }}; Userspace {{ name: {space}; size: {size} }}",
-        user = acc.user.username,
-        space = acc.userspace.name,
-        size = acc.userspace.size_mb,
-    );
+    #[structopt(short, long, parse(from_os_str))]
+    mountbase: Option<PathBuf>,
 
-    Ok(())
-}
+    #[structopt(long, parse(from_os_str))]
+    sshd_config_dir: Option<PathBuf>,
 
-fn create_user(opt: &Opt) -> Result<User, AppError> {
-    // Prepare arguments
-    let username = &opt.username;
-    let default_home_directory = || "/home".to_string();
-    let base_directory = opt
-        .base
-        .clone()
-        .map(|p| {
-            p.to_str()
-                .map(|s| s.to_string())
-                .unwrap_or_else(default_home_directory)
-        })
-        .unwrap_or_else(default_home_directory);
-
-    // Create user
-    invoke_create_user(&opt.username, &base_directory)?;
-
-    // Log
-    println!(
-        "User created: {user} ({base_dir}/{user})",
-        user = username,
-        base_dir = base_directory
-    );
+    #[structopt(long, parse(from_os_str))]
+    inventory: Option<PathBuf>,
 
-    // Instantiate data structure
-    Ok(User {
-        username: username.to_string(),
-        home_directory: format!("{}/{}", base_directory, username),
-        base_directory: base_directory,
-    })
-}
+    /// Directory holding the images of the `image` backend
+    #[structopt(long, parse(from_os_str))]
+    image_store: Option<PathBuf>,
 
-fn invoke_create_user(username: &str, home_directory: &str) -> Result<(), AppError> {
-    let mut cmd = Command::new("useradd");
-    cmd.args(&["--base-dir", home_directory]);
-    cmd.args(&["--comment", &format!("mkwebuser {user}", user = username)]);
-    cmd.args(&["--inactive", "-1"]); // never mark user as inactive
-    cmd.args(&["--shell", "/usr/sbin/nologin"]); // no interactive shell
-    cmd.arg("--create-home"); // create home directory
-    cmd.arg(username);
-    let status: ExitStatus = cmd.status().map_err(|_| AppError::UserCreationFailed {
-        reason: "Unable to get exit status",
-    })?;
-    if status.success() {
-        Ok(())
-    } else {
-        // https://linux.die.net/man/8/useradd
-        Err(match status.code() {
-            Some(1) => AppError::UserCreationFailed {
-                reason: "Unable to update password file",
-            },
-            Some(2) => AppError::UserCreationFailed {
-                reason: "Invalid command syntax",
-            },
-            Some(3) => AppError::UserCreationFailed {
-                reason: "Invalid argument to option",
-            },
-            Some(4) => AppError::UserCreationFailed {
-                reason: "UID already in use",
-            },
-            Some(6) => AppError::UserCreationFailed {
-                reason: "The specified group does not exist",
-            },
-            Some(9) => AppError::UserCreationFailed {
-                reason: "Username already in use",
-            },
-            Some(10) => AppError::UserCreationFailed {
-                reason: "Failed to update group file",
-            },
-            Some(12) => AppError::UserCreationFailed {
-                reason: "Failed to create home directory",
-            },
-            Some(13) => AppError::UserCreationFailed {
-                reason: "Failed to create mail spool",
-            },
-            Some(14) => AppError::UserCreationFailed {
-                reason: "Failed to update SELinux user mapping",
-            },
-            None => AppError::UserCreationFailed {
-                reason: "Process terminated by signal",
-            },
-            _ => AppError::UserCreationFailed { reason: "Unknown" },
-        })
-    }
-}
+    /// Volume group for accounts of the `lvm` backend [default: provme]
+    #[structopt(long)]
+    volume_group: Option<String>,
 
-fn create_user_space(opt: &Opt, user: &User) -> Result<UserSpace, AppError> {
-    // Prepare arguments
-    let name = "volume";
-    let path = format!(
-        "{home_dir}/{name}",
-        home_dir = user.home_directory,
-        name = name
-    );
-    let quota = opt.quota.unwrap_or(1024_u64);
-    // Create user space
-    invoke_create_user_space(&path, quota)?;
+    /// Public key the user can log in with; can be repeated
+    #[structopt(long, number_of_values = 1)]
+    ssh_key: Vec<SshKey>,
+
+    /// File with public keys the user can log in with; can be repeated
+    #[structopt(long, number_of_values = 1, parse(from_os_str))]
+    ssh_key_file: Vec<PathBuf>,
 
-    // Log
-    println!("Space created: {size}M ({path})", size = quota, path = path,);
+    /// Directory holding the authorized_keys files, outside of the chroots
+    #[structopt(long, parse(from_os_str))]
+    authorized_keys_dir: Option<PathBuf>,
 
-    // Format user space
-    invoke_format_user_space(&path)?;
+    /// Read the password of the user from the first line of stdin
+    #[structopt(long, conflicts_with = "generate-password")]
+    password_stdin: bool,
 
-    // Log
-    println!("Space formatted: ext4 ({path})", path = path,);
+    /// Set a random password and print it
+    #[structopt(long)]
+    generate_password: bool,
 
-    // Instantiate data structure
-    Ok(UserSpace {
-        name: name.to_string(),
-        path: path,
-        size_mb: quota,
-    })
+    /// Print every action instead of executing it
+    #[structopt(long)]
+    dry_run: bool,
 }
 
-fn invoke_create_user_space<P>(path: &P, quota_mb: u64) -> Result<(), AppError>
-where
-    P: AsRef<str>,
-{
-    let path: &str = path.as_ref();
-    let mut cmd = Command::new("dd");
-    cmd.arg(format!("if=/dev/zero"));
-    cmd.arg(format!("of={path}", path = path));
-    cmd.arg(format!("bs={size}M", size = quota_mb));
-    cmd.arg("count=1");
-    cmd.stderr(Stdio::null());
-    cmd.stdout(Stdio::null());
-    let status: ExitStatus = cmd
-        .status()
-        .map_err(|_| AppError::UserSpaceCreationFailed {
-            reason: "Unable to get exit status",
-        })?;
-    if status.success() {
-        Ok(())
-    } else {
-        Err(AppError::UserSpaceCreationFailed { reason: "dd error" })
+fn main() {
+    if let Err(err) = run() {
+        eprintln!("[ERROR] {}", err);
+        process::exit(err.exit_code());
     }
 }
 
-fn invoke_format_user_space<P>(path: &P) -> Result<(), AppError>
-where
-    P: AsRef<str>,
-{
-    let path: &str = path.as_ref();
-    let mut cmd = Command::new("mkfs.ext4");
-    cmd.arg(path);
-    cmd.stderr(Stdio::null());
-    cmd.stdout(Stdio::null());
-    let status: ExitStatus = cmd
-        .status()
-        .map_err(|_| AppError::UserSpaceFormattingFailed {
-            reason: "Unable to get exit status",
-        })?;
-    if status.success() {
-        Ok(())
+fn run() -> Result<(), Error> {
+    // Parse arguments
+    let opt: Opt = Opt::from_args();
+    let mut ssh_keys = opt.ssh_key.clone();
+    for path in &opt.ssh_key_file {
+        ssh_keys.extend(load_ssh_keys(&path_string(path)?)?);
+    }
+    let password = if opt.password_stdin {
+        Some(Password::read(&mut io::stdin().lock())?)
+    } else if opt.generate_password {
+        Some(Password::generate()?)
     } else {
-        Err(AppError::UserSpaceFormattingFailed {
-            reason: "mkfs.ext4 error",
-        })
+        None
+    };
+    let request = ProvisionRequest {
+        username: opt.username.clone(),
+        quota_mb: opt.quota.unwrap_or(1024_u64),
+        allocation: opt.allocation,
+        backend: opt.backend,
+        format: FormatOptions {
+            filesystem: opt.filesystem,
+            reserved_percent: opt.reserved_blocks,
+            inode_ratio: opt.inode_ratio,
+            inode_count: opt.inodes,
+            label: opt.label.clone(),
+            features: opt.ext4_features.clone(),
+        },
+        ssh_keys,
+        password: password.clone(),
+        layout: Layout::from_options(&LayoutOptions {
+            base_directory: opt.base.clone(),
+            mount_base: opt.mountbase.clone(),
+            sshd_config_dir: opt.sshd_config_dir.clone(),
+            inventory_path: opt.inventory.clone(),
+            image_store: opt.image_store.clone(),
+            volume_group: opt.volume_group.clone(),
+            authorized_keys_dir: opt.authorized_keys_dir.clone(),
+        })?,
+    };
+    let runner: &dyn Runner = if opt.dry_run {
+        &DryRunRunner
+    } else {
+        &SystemRunner
+    };
+
+    // Create web space account, undoing everything on failure
+    let acc = provision(runner, &request)?;
+
+    if opt.dry_run {
+        println!("[DRY-RUN] No changes were made");
+        return Ok(());
     }
+
+    println!(
+        "[SUCCESS] User {{ name: {user} }}; Userspace {{ name: {space}; size: {size}; mount: {mount} }}; Jail {{ chroot: {chroot} }}",
+        user = acc.user.username,
+        space = acc.userspace.name,
+        size = acc.userspace.size_mb,
+        mount = acc.userspace.mount_point,
+        chroot = acc.jail.chroot_directory,
+    );
+    if let (true, Some(password)) = (opt.generate_password, &password) {
+        println!("Password: {password}", password = password.as_str());
+    }
+
+    Ok(())
 }
@@ -0,0 +1,413 @@
+use provme::{
+    add_ssh_keys, apply, list_accounts, list_ssh_keys, load_ssh_keys, migrate, passwd, path_string,
+    remount_all, remove_ssh_keys, resize, validate_layout, AccountStatus, DryRunRunner, Error,
+    Inventory, Layout, LayoutOptions, Manifest, MigrateRequest, PasswdRequest, Password, Plan,
+    ResizeRequest, Runner, SshKey, SystemRunner,
+};
+use std::io;
+use std::path::PathBuf;
+use std::process;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+#[structopt(name = "provme")]
+enum Opt {
+    /// List provisioned accounts with their live usage
+    List {
+        #[structopt(flatten)]
+        layout: LayoutOpt,
+
+        /// Print JSON instead of a table
+        #[structopt(long)]
+        json: bool,
+    },
+
+    /// Grow or shrink the quota of an account
+    Resize {
+        #[structopt(flatten)]
+        layout: LayoutOpt,
+
+        #[structopt(short, long)]
+        username: String,
+
+        /// New quota in MiB
+        #[structopt(short, long)]
+        quota: u64,
+
+        /// Print every action instead of executing it
+        #[structopt(long)]
+        dry_run: bool,
+    },
+
+    /// Create and resize accounts to match a TOML manifest
+    Apply {
+        #[structopt(flatten)]
+        layout: LayoutOpt,
+
+        /// Manifest listing the desired accounts
+        #[structopt(parse(from_os_str))]
+        manifest: PathBuf,
+
+        /// Also remove inventory accounts missing from the manifest
+        #[structopt(long)]
+        prune: bool,
+
+        /// Print every action instead of executing it
+        #[structopt(long)]
+        dry_run: bool,
+    },
+
+    /// Move images of existing accounts into the image store
+    Migrate {
+        #[structopt(flatten)]
+        layout: LayoutOpt,
+
+        /// Only migrate this account
+        #[structopt(short, long)]
+        username: Option<String>,
+
+        /// Print every action instead of executing it
+        #[structopt(long)]
+        dry_run: bool,
+    },
+
+    /// Mount every account space that is not mounted, e.g. after a reboot
+    RemountAll {
+        #[structopt(flatten)]
+        layout: LayoutOpt,
+
+        /// Print every action instead of executing it
+        #[structopt(long)]
+        dry_run: bool,
+    },
+
+    /// Add, remove or list the public keys of an account
+    Keys(KeysOpt),
+
+    /// Set a new random password for an account and print it
+    Passwd {
+        #[structopt(flatten)]
+        layout: LayoutOpt,
+
+        #[structopt(short, long)]
+        username: String,
+
+        /// Read the new password from the first line of stdin instead
+        #[structopt(long, conflicts_with = "disable")]
+        stdin: bool,
+
+        /// Lock the password and refuse password authentication
+        #[structopt(long)]
+        disable: bool,
+
+        /// Print every action instead of executing it
+        #[structopt(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(StructOpt)]
+enum KeysOpt {
+    /// Authorize public keys for an account
+    Add {
+        #[structopt(flatten)]
+        layout: LayoutOpt,
+
+        #[structopt(short, long)]
+        username: String,
+
+        /// Public key; can be repeated
+        #[structopt(long, number_of_values = 1)]
+        key: Vec<SshKey>,
+
+        /// File with public keys; can be repeated
+        #[structopt(long, number_of_values = 1, parse(from_os_str))]
+        key_file: Vec<PathBuf>,
+
+        /// Print every action instead of executing it
+        #[structopt(long)]
+        dry_run: bool,
+    },
+
+    /// Revoke public keys of an account
+    Remove {
+        #[structopt(flatten)]
+        layout: LayoutOpt,
+
+        #[structopt(short, long)]
+        username: String,
+
+        /// Public key, key data or key comment; can be repeated
+        #[structopt(long, number_of_values = 1, required = true)]
+        key: Vec<String>,
+
+        /// Print every action instead of executing it
+        #[structopt(long)]
+        dry_run: bool,
+    },
+
+    /// List the public keys of an account
+    List {
+        #[structopt(flatten)]
+        layout: LayoutOpt,
+
+        #[structopt(short, long)]
+        username: String,
+    },
+}
+
+#[derive(StructOpt)]
+struct LayoutOpt {
+    #[structopt(short, long, parse(from_os_str))]
+    base: Option<PathBuf>,
+
+    #[structopt(short, long, parse(from_os_str))]
+    mountbase: Option<PathBuf>,
+
+    #[structopt(long, parse(from_os_str))]
+    sshd_config_dir: Option<PathBuf>,
+
+    #[structopt(long, parse(from_os_str))]
+    inventory: Option<PathBuf>,
+
+    /// Directory holding the images of the `image` backend
+    #[structopt(long, parse(from_os_str))]
+    image_store: Option<PathBuf>,
+
+    /// Volume group for accounts of the `lvm` backend [default: provme]
+    #[structopt(long)]
+    volume_group: Option<String>,
+
+    /// Directory holding the authorized_keys files, outside of the chroots
+    #[structopt(long, parse(from_os_str))]
+    authorized_keys_dir: Option<PathBuf>,
+}
+
+impl LayoutOpt {
+    fn layout(&self) -> Result<Layout, Error> {
+        let layout = Layout::from_options(&LayoutOptions {
+            base_directory: self.base.clone(),
+            mount_base: self.mountbase.clone(),
+            sshd_config_dir: self.sshd_config_dir.clone(),
+            inventory_path: self.inventory.clone(),
+            image_store: self.image_store.clone(),
+            volume_group: self.volume_group.clone(),
+            authorized_keys_dir: self.authorized_keys_dir.clone(),
+        })?;
+        validate_layout(&layout)?;
+        Ok(layout)
+    }
+}
+
+fn main() {
+    if let Err(err) = run() {
+        eprintln!("[ERROR] {}", err);
+        process::exit(err.exit_code());
+    }
+}
+
+fn run() -> Result<(), Error> {
+    // Parse arguments
+    match Opt::from_args() {
+        Opt::List { layout, json } => list(&layout.layout()?, json),
+        Opt::Resize {
+            layout,
+            username,
+            quota,
+            dry_run,
+        } => {
+            let request = ResizeRequest {
+                username,
+                quota_mb: quota,
+                layout: layout.layout()?,
+            };
+            let record = resize(runner(dry_run), &request)?;
+            if dry_run {
+                println!("[DRY-RUN] No changes were made");
+                return Ok(());
+            }
+            println!(
+                "[SUCCESS] User {{ name: {user} }}; Userspace {{ size: {size} }}",
+                user = record.username,
+                size = record.size_mb,
+            );
+            Ok(())
+        }
+        Opt::Apply {
+            layout,
+            manifest,
+            prune,
+            dry_run,
+        } => {
+            let layout = layout.layout()?;
+            let manifest = Manifest::load(&path_string(&manifest)?)?;
+            let inventory = Inventory::load(&layout.inventory_path)?;
+            let plan = Plan::new(manifest.requests(&layout)?, &inventory, prune)?;
+
+            // Log
+            if plan.is_empty() {
+                println!("No changes");
+                return Ok(());
+            }
+            print!("{}", plan);
+
+            apply(runner(dry_run), &plan, &layout)?;
+            if dry_run {
+                println!("[DRY-RUN] No changes were made");
+                return Ok(());
+            }
+            println!(
+                "[SUCCESS] Applied {count} change(s)",
+                count = plan.changes.len()
+            );
+            Ok(())
+        }
+        Opt::Migrate {
+            layout,
+            username,
+            dry_run,
+        } => {
+            let request = MigrateRequest {
+                username,
+                layout: layout.layout()?,
+            };
+            let migrated = migrate(runner(dry_run), &request)?;
+            if dry_run {
+                println!("[DRY-RUN] No changes were made");
+                return Ok(());
+            }
+            println!(
+                "[SUCCESS] Migrated {count} image(s)",
+                count = migrated.len()
+            );
+            Ok(())
+        }
+        Opt::RemountAll { layout, dry_run } => {
+            let remounted = remount_all(runner(dry_run), &layout.layout()?)?;
+            if dry_run {
+                println!("[DRY-RUN] No changes were made");
+                return Ok(());
+            }
+            println!(
+                "[SUCCESS] Remounted {count} space(s)",
+                count = remounted.len()
+            );
+            Ok(())
+        }
+        Opt::Keys(KeysOpt::Add {
+            layout,
+            username,
+            mut key,
+            key_file,
+            dry_run,
+        }) => {
+            for path in &key_file {
+                key.extend(load_ssh_keys(&path_string(path)?)?);
+            }
+            let keys = add_ssh_keys(runner(dry_run), &layout.layout()?, &username, &key)?;
+            print_keys_result(&username, keys.len(), dry_run);
+            Ok(())
+        }
+        Opt::Keys(KeysOpt::Remove {
+            layout,
+            username,
+            key,
+            dry_run,
+        }) => {
+            let keys = remove_ssh_keys(runner(dry_run), &layout.layout()?, &username, &key)?;
+            print_keys_result(&username, keys.len(), dry_run);
+            Ok(())
+        }
+        Opt::Passwd {
+            layout,
+            username,
+            stdin,
+            disable,
+            dry_run,
+        } => {
+            let password = if disable {
+                None
+            } else if stdin {
+                Some(Password::read(&mut io::stdin().lock())?)
+            } else {
+                Some(Password::generate()?)
+            };
+            let request = PasswdRequest {
+                username,
+                password,
+                layout: layout.layout()?,
+            };
+            passwd(runner(dry_run), &request)?;
+            if dry_run {
+                println!("[DRY-RUN] No changes were made");
+                return Ok(());
+            }
+            println!(
+                "[SUCCESS] User {{ name: {user} }}; Password {{ authentication: {enabled} }}",
+                user = request.username,
+                enabled = if disable { "no" } else { "yes" },
+            );
+            if let (false, Some(password)) = (stdin, &request.password) {
+                println!("Password: {password}", password = password.as_str());
+            }
+            Ok(())
+        }
+        Opt::Keys(KeysOpt::List { layout, username }) => {
+            for key in list_ssh_keys(&layout.layout()?, &username)? {
+                println!("{}", key);
+            }
+            Ok(())
+        }
+    }
+}
+
+fn runner(dry_run: bool) -> &'static dyn Runner {
+    if dry_run {
+        &DryRunRunner
+    } else {
+        &SystemRunner
+    }
+}
+
+fn print_keys_result(username: &str, count: usize, dry_run: bool) {
+    if dry_run {
+        println!("[DRY-RUN] No changes were made");
+        return;
+    }
+    println!(
+        "[SUCCESS] User {{ name: {user} }}; Keys {{ count: {count} }}",
+        user = username,
+        count = count,
+    );
+}
+
+fn list(layout: &Layout, json: bool) -> Result<(), Error> {
+    let accounts = list_accounts(layout)?;
+    if json {
+        let json = serde_json::to_string_pretty(&accounts).map_err(|_| Error::StatusFailed {
+            reason: "Unable to serialize accounts",
+        })?;
+        println!("{}", json);
+    } else {
+        print_table(&accounts);
+    }
+    Ok(())
+}
+
+fn print_table(accounts: &[AccountStatus]) {
+    println!("USER                 QUOTA      USED      FREE MOUNTED JAILED TRACKED");
+    let mb = |value: Option<u64>| value.map_or("-".to_string(), |v| format!("{}M", v));
+    let yes_no = |value: bool| if value { "yes" } else { "no" };
+    for acc in accounts {
+        println!(
+            "{:<16} {:>9} {:>9} {:>9} {:<7} {:<6} {}",
+            acc.username,
+            mb(acc.quota_mb),
+            mb(acc.usage.map(|u| u.used_mb)),
+            mb(acc.usage.map(|u| u.free_mb)),
+            yes_no(acc.mounted),
+            yes_no(acc.jailed),
+            yes_no(acc.tracked),
+        );
+    }
+}
@@ -0,0 +1,557 @@
+use crate::{
+    btrfs_quotas_enabled, create_btrfs_space, create_lvm_space, create_mount_unit,
+    create_quota_space, create_ssh_keys, create_user, create_user_jail, create_user_space,
+    create_xfs_space, delete_btrfs_space, delete_lvm_space, delete_mount_unit, delete_quota_space,
+    delete_ssh_keys, delete_user, delete_user_jail, delete_user_space, delete_xfs_space,
+    foreign_user, forget_account, path_or_default, record_account, set_password, user_id,
+    validate_layout, validate_path, validate_username, Allocation, Backend, Error, FormatOptions,
+    Inventory, Password, Runner, SshKey, Transaction, User, UserJail, UserSpace,
+};
+use std::fs;
+use std::path::PathBuf;
+
+/// Host directories under which account resources are placed.
+#[derive(Debug, Clone)]
+pub struct Layout {
+    pub base_directory: String,
+    pub mount_base: String,
+    pub sshd_config_dir: String,
+    pub inventory_path: String,
+    /// Directory holding the images of the image backend.
+    pub image_store: String,
+    /// Volume group that logical volumes of the LVM backend are carved from.
+    pub volume_group: String,
+    /// Directory holding the authorized_keys files of the accounts, outside
+    /// of their chroots.
+    pub authorized_keys_dir: String,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout {
+            base_directory: "/home".to_string(),
+            mount_base: "/mnt".to_string(),
+            sshd_config_dir: "/etc/ssh/sshd_config.d".to_string(),
+            inventory_path: "/var/lib/provme/inventory.json".to_string(),
+            image_store: "/srv/provme/images".to_string(),
+            volume_group: "provme".to_string(),
+            authorized_keys_dir: "/etc/ssh/authorized_keys".to_string(),
+        }
+    }
+}
+
+impl Layout {
+    /// Builds a layout from command line options, keeping the default of
+    /// every option that is missing.
+    pub fn from_options(options: &LayoutOptions) -> Result<Layout, Error> {
+        let default = Layout::default();
+        Ok(Layout {
+            base_directory: path_or_default(&options.base_directory, default.base_directory)?,
+            mount_base: path_or_default(&options.mount_base, default.mount_base)?,
+            sshd_config_dir: path_or_default(&options.sshd_config_dir, default.sshd_config_dir)?,
+            inventory_path: path_or_default(&options.inventory_path, default.inventory_path)?,
+            image_store: path_or_default(&options.image_store, default.image_store)?,
+            volume_group: options.volume_group.clone().unwrap_or(default.volume_group),
+            authorized_keys_dir: path_or_default(
+                &options.authorized_keys_dir,
+                default.authorized_keys_dir,
+            )?,
+        })
+    }
+}
+
+/// Layout directories and volume group as given on the command line.
+#[derive(Debug, Clone, Default)]
+pub struct LayoutOptions {
+    pub base_directory: Option<PathBuf>,
+    pub mount_base: Option<PathBuf>,
+    pub sshd_config_dir: Option<PathBuf>,
+    pub inventory_path: Option<PathBuf>,
+    pub image_store: Option<PathBuf>,
+    pub volume_group: Option<String>,
+    pub authorized_keys_dir: Option<PathBuf>,
+}
+
+/// Everything needed to provision a single web space account.
+#[derive(Debug, Clone)]
+pub struct ProvisionRequest {
+    pub username: String,
+    pub quota_mb: u64,
+    pub allocation: Allocation,
+    pub backend: Backend,
+    /// How image and LVM spaces are formatted.
+    pub format: FormatOptions,
+    /// Public keys the user can log in with.
+    pub ssh_keys: Vec<SshKey>,
+    /// Password the user can log in with. Without one, sshd refuses
+    /// password authentication for the user.
+    pub password: Option<Password>,
+    pub layout: Layout,
+}
+
+impl ProvisionRequest {
+    /// A request for a 1024 MiB preallocated ext4 image in the default layout.
+    pub fn new(username: &str) -> Self {
+        ProvisionRequest {
+            username: username.to_string(),
+            quota_mb: 1024,
+            allocation: Allocation::Preallocated,
+            backend: Backend::Image,
+            format: FormatOptions::default(),
+            ssh_keys: Vec::new(),
+            password: None,
+            layout: Layout::default(),
+        }
+    }
+}
+
+/// Everything needed to remove a web space account.
+#[derive(Debug, Clone)]
+pub struct DeprovisionRequest {
+    pub username: String,
+    pub layout: Layout,
+    /// Move the image into this directory instead of deleting it.
+    pub archive_directory: Option<String>,
+}
+
+impl DeprovisionRequest {
+    pub fn new(username: &str) -> Self {
+        DeprovisionRequest {
+            username: username.to_string(),
+            layout: Layout::default(),
+            archive_directory: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct WebSpaceAccount {
+    pub user: User,
+    pub userspace: UserSpace,
+    pub jail: UserJail,
+}
+
+/// Provisions a web space account and records it in the inventory.
+///
+/// Steps that an earlier, interrupted run already completed are detected
+/// and skipped, so provisioning the same account again is safe. If any step
+/// fails, every step this run completed is undone and
+/// [`Error::ProvisioningRolledBack`] is returned.
+pub fn provision(
+    runner: &dyn Runner,
+    request: &ProvisionRequest,
+) -> Result<WebSpaceAccount, Error> {
+    validate_username(&request.username)?;
+    validate_layout(&request.layout)?;
+    request.format.validate()?;
+    let passwd = fs::read_to_string("/etc/passwd").map_err(|_| Error::UserCreationFailed {
+        reason: "Unable to read passwd database",
+    })?;
+    let mounts =
+        fs::read_to_string("/proc/self/mounts").map_err(|_| Error::UserSpaceMountingFailed {
+            reason: "Unable to read mount table",
+        })?;
+    let mut tx = Transaction::new();
+    create_account(runner, request, &passwd, &mounts, &mut tx)
+        .and_then(|acc| {
+            record_account(runner, &request.layout.inventory_path, &acc)?;
+            Ok(acc)
+        })
+        .map_err(|err| tx.rollback(runner, err))
+}
+
+/// Removes a web space account created by [`provision`], in reverse order,
+/// and drops it from the inventory.
+///
+/// Steps whose resources are already gone are skipped, so partially
+/// provisioned accounts can be removed as well. Accounts missing from the
+/// inventory are assumed to use the image backend. Users that were not
+/// created by mkwebuser are refused.
+pub fn deprovision(runner: &dyn Runner, request: &DeprovisionRequest) -> Result<(), Error> {
+    validate_username(&request.username)?;
+    validate_layout(&request.layout)?;
+    if let Some(archive_directory) = &request.archive_directory {
+        validate_path(archive_directory)?;
+    }
+    let passwd = fs::read_to_string("/etc/passwd").map_err(|_| Error::UserDeletionFailed {
+        reason: "Unable to read passwd database",
+    })?;
+    if foreign_user(&passwd, &request.username) {
+        return Err(Error::ValidationFailed {
+            reason: "User was not created by mkwebuser",
+        });
+    }
+    let backend = Inventory::load(&request.layout.inventory_path)?
+        .accounts
+        .get(&request.username)
+        .map_or(Backend::Image, |record| record.backend);
+    delete_account(
+        runner,
+        &request.username,
+        &request.layout,
+        backend,
+        request.archive_directory.as_deref(),
+    )?;
+    forget_account(runner, &request.layout.inventory_path, &request.username)
+}
+
+/// Creates every part of an account that `passwd` and `mounts`, the
+/// contents of `/etc/passwd` and `/proc/self/mounts`, do not show yet.
+pub fn create_account(
+    runner: &dyn Runner,
+    request: &ProvisionRequest,
+    passwd: &str,
+    mounts: &str,
+    tx: &mut Transaction,
+) -> Result<WebSpaceAccount, Error> {
+    let layout = &request.layout;
+
+    // Create user
+    let user = create_user(
+        runner,
+        &request.username,
+        &layout.base_directory,
+        passwd,
+        tx,
+    )?;
+
+    // Create user space with quota
+    let userspace = match request.backend {
+        Backend::Image => create_user_space(
+            runner,
+            &user,
+            request.quota_mb,
+            request.allocation,
+            &request.format,
+            &layout.image_store,
+            &layout.mount_base,
+            mounts,
+            tx,
+        )?,
+        Backend::Quota => create_quota_space(runner, &user, request.quota_mb, mounts, tx)?,
+        Backend::Xfs => {
+            // A user created above only shows up in the passwd database now
+            let passwd = match user_id(passwd, &user.username) {
+                Some(_) => passwd.to_string(),
+                None => fs::read_to_string("/etc/passwd").map_err(|_| {
+                    Error::UserSpaceCreationFailed {
+                        reason: "Unable to read passwd database",
+                    }
+                })?,
+            };
+            create_xfs_space(runner, &user, request.quota_mb, &passwd, mounts, tx)?
+        }
+        Backend::Btrfs => create_btrfs_space(
+            runner,
+            &user,
+            request.quota_mb,
+            &layout.mount_base,
+            mounts,
+            btrfs_quotas_enabled(&layout.mount_base),
+            tx,
+        )?,
+        Backend::Lvm => create_lvm_space(
+            runner,
+            &user,
+            request.quota_mb,
+            &request.format,
+            &layout.volume_group,
+            &layout.mount_base,
+            mounts,
+            tx,
+        )?,
+    };
+
+    // Mount user space on boot
+    if let Backend::Image | Backend::Lvm = request.backend {
+        create_mount_unit(
+            runner,
+            &userspace.path,
+            &userspace.mount_point,
+            &userspace.filesystem,
+            request.backend == Backend::Image,
+            tx,
+        )?;
+    }
+
+    // Authorize keys of user
+    create_ssh_keys(
+        runner,
+        &user.username,
+        &request.ssh_keys,
+        &layout.authorized_keys_dir,
+        tx,
+    )?;
+
+    // Jail user to user space
+    let jail = create_user_jail(
+        runner,
+        &user,
+        &userspace,
+        &layout.sshd_config_dir,
+        &layout.authorized_keys_dir,
+        request.password.is_some(),
+        tx,
+    )?;
+
+    // Set password of user last, since it cannot be undone
+    if let Some(password) = &request.password {
+        set_password(runner, &user.username, password)?;
+    }
+
+    // Instantiate data structure
+    Ok(WebSpaceAccount {
+        user,
+        userspace,
+        jail,
+    })
+}
+
+pub fn delete_account(
+    runner: &dyn Runner,
+    username: &str,
+    layout: &Layout,
+    backend: Backend,
+    archive_directory: Option<&str>,
+) -> Result<(), Error> {
+    if backend != Backend::Image && archive_directory.is_some() {
+        return Err(Error::UserSpaceDeletionFailed {
+            reason: "Archiving requires the image backend",
+        });
+    }
+
+    // Remove sshd configuration
+    delete_user_jail(runner, username, &layout.sshd_config_dir)?;
+
+    // Remove authorized keys
+    delete_ssh_keys(runner, username, &layout.authorized_keys_dir)?;
+
+    // Stop mounting user space on boot
+    if let Backend::Image | Backend::Lvm = backend {
+        let mount_point = format!("{}/{}", layout.mount_base, username);
+        delete_mount_unit(runner, &mount_point)?;
+    }
+
+    // Release user space
+    let home_directory = format!("{}/{}", layout.base_directory, username);
+    match backend {
+        Backend::Image => delete_user_space(
+            runner,
+            username,
+            &home_directory,
+            &layout.image_store,
+            &layout.mount_base,
+            archive_directory,
+        )?,
+        Backend::Quota => delete_quota_space(runner, username, &home_directory)?,
+        Backend::Xfs => delete_xfs_space(runner, username, &home_directory)?,
+        Backend::Btrfs => delete_btrfs_space(runner, username, &layout.mount_base)?,
+        Backend::Lvm => {
+            delete_lvm_space(runner, username, &layout.volume_group, &layout.mount_base)?
+        }
+    }
+
+    // Delete user
+    delete_user(runner, username)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{image_path, RecordingRunner};
+    use std::{env, process};
+
+    /// Host state without any trace of `bob`.
+    const PASSWD: &str = "root:x:0:0:root:/root:/bin/bash\n";
+    const MOUNTS: &str = "/dev/sda1 / ext4 rw,relatime 0 0\n";
+
+    fn request() -> ProvisionRequest {
+        let mut request = ProvisionRequest::new("bob");
+        request.layout.inventory_path = "/nonexistent/provme/inventory.json".to_string();
+        request
+    }
+
+    #[test]
+    fn layout_options_override_defaults() {
+        let layout = Layout::from_options(&LayoutOptions {
+            mount_base: Some(PathBuf::from("/srv/mnt")),
+            volume_group: Some("web".to_string()),
+            ..LayoutOptions::default()
+        })
+        .unwrap();
+
+        assert_eq!(layout.mount_base, "/srv/mnt");
+        assert_eq!(layout.volume_group, "web");
+        assert_eq!(layout.base_directory, Layout::default().base_directory);
+        assert_eq!(layout.image_store, Layout::default().image_store);
+    }
+
+    #[test]
+    fn provision_validates_before_running_anything() {
+        let runner = RecordingRunner::new();
+        let mut bad_username = request();
+        bad_username.username = "../etc".to_string();
+        let mut bad_path = request();
+        bad_path.layout.base_directory = "/home/../etc".to_string();
+
+        assert!(matches!(
+            provision(&runner, &bad_username),
+            Err(Error::ValidationFailed { .. })
+        ));
+        assert!(matches!(
+            provision(&runner, &bad_path),
+            Err(Error::ValidationFailed { .. })
+        ));
+        assert!(runner.actions().is_empty());
+    }
+
+    #[test]
+    fn provision_runs_every_step_in_order() {
+        let runner = RecordingRunner::new().respond("losetup --find", 0, "/dev/loop0\n");
+        let mut tx = Transaction::new();
+
+        let acc = create_account(&runner, &request(), PASSWD, MOUNTS, &mut tx).unwrap();
+
+        assert_eq!(acc.user.username, "bob");
+        assert_eq!(acc.userspace.size_mb, 1024);
+        assert_eq!(acc.jail.chroot_directory, "/mnt/bob");
+        assert_eq!(
+            runner.actions(),
+            vec![
+                "useradd --base-dir /home --comment 'mkwebuser bob' --inactive -1 \
+                 --shell /usr/sbin/nologin --create-home bob",
+                "mkdir --parents /srv/provme/images",
+                "chmod 0700 /srv/provme/images",
+                "fallocate --length 1073741824 /srv/provme/images/bob.img",
+                "mkfs.ext4 /srv/provme/images/bob.img",
+                "losetup --find --show /srv/provme/images/bob.img",
+                "mkdir --parents /mnt/bob",
+                "mount --types ext4 /dev/loop0 /mnt/bob",
+                "write /etc/systemd/system/mnt-bob.mount",
+                "systemctl daemon-reload",
+                "systemctl enable mnt-bob.mount",
+                "mkdir --parents /mnt/bob/www",
+                "chown bob:bob /mnt/bob/www",
+                "write /etc/ssh/sshd_config.d/mkwebuser-bob.conf",
+                "sshd -t",
+                "systemctl reload sshd",
+            ]
+        );
+    }
+
+    #[test]
+    fn passwords_enable_password_authentication() {
+        let runner = RecordingRunner::new().respond("losetup --find", 0, "/dev/loop0\n");
+        let mut request = request();
+        request.password = Some(Password::new("correct horse").unwrap());
+        let mut tx = Transaction::new();
+
+        create_account(&runner, &request, PASSWD, MOUNTS, &mut tx).unwrap();
+
+        assert_eq!(
+            runner.actions().last().unwrap(),
+            "chpasswd < <hidden input>"
+        );
+        let config = runner
+            .file("/etc/ssh/sshd_config.d/mkwebuser-bob.conf")
+            .unwrap();
+        assert!(config.contains("    PasswordAuthentication yes\n"));
+    }
+
+    #[test]
+    fn create_account_completes_partial_account() {
+        let image_store = env::temp_dir().join(format!("provme-account-{}", process::id()));
+        fs::create_dir_all(&image_store).unwrap();
+        let mut request = request();
+        request.layout.image_store = image_store.to_str().unwrap().to_string();
+        let path = image_path(&request.layout.image_store, "bob");
+        // A sparse image without a filesystem, left behind before mkfs
+        fs::File::create(&path)
+            .and_then(|file| file.set_len(1024 * 1024 * 1024))
+            .unwrap();
+        let runner = RecordingRunner::new().respond(
+            "losetup --associated",
+            0,
+            &format!("/dev/loop0: [2049]:12 ({})\n", path),
+        );
+        let passwd = "bob:x:1001:1001:mkwebuser bob:/home/bob:/usr/sbin/nologin\n";
+        let mounts = "/dev/loop0 /mnt/bob ext4 rw,relatime 0 0\n";
+        let mut tx = Transaction::new();
+
+        let result = create_account(&runner, &request, passwd, mounts, &mut tx);
+        fs::remove_dir_all(&image_store).unwrap();
+
+        let acc = result.unwrap();
+        assert_eq!(acc.userspace.loop_device.as_deref(), Some("/dev/loop0"));
+        assert_eq!(
+            runner.actions()[..2],
+            [
+                format!("losetup --associated {}", path),
+                format!("mkfs.ext4 {}", path),
+            ]
+        );
+        assert_eq!(
+            runner.actions()[2..],
+            [
+                "write /etc/systemd/system/mnt-bob.mount",
+                "systemctl daemon-reload",
+                "systemctl enable mnt-bob.mount",
+                "mkdir --parents /mnt/bob/www",
+                "chown bob:bob /mnt/bob/www",
+                "write /etc/ssh/sshd_config.d/mkwebuser-bob.conf",
+                "sshd -t",
+                "systemctl reload sshd",
+            ]
+        );
+    }
+
+    #[test]
+    fn create_account_refuses_foreign_mounts() {
+        let runner = RecordingRunner::new();
+        let passwd = "bob:x:1001:1001:mkwebuser bob:/home/bob:/usr/sbin/nologin\n";
+        let mounts = "/dev/sdc1 /mnt/bob ext4 rw,relatime 0 0\n";
+        let mut tx = Transaction::new();
+
+        let result = create_account(&runner, &request(), passwd, mounts, &mut tx);
+
+        assert!(matches!(
+            result,
+            Err(Error::UserSpaceMountingFailed {
+                reason: "Mount point is in use by another device"
+            })
+        ));
+        assert!(runner.actions().is_empty());
+    }
+
+    #[test]
+    fn failed_provisioning_is_rolled_back_completely() {
+        let runner = RecordingRunner::new()
+            .respond("losetup --find", 0, "/dev/loop0\n")
+            .respond("sshd -t", 255, "");
+        let mut tx = Transaction::new();
+
+        let err = create_account(&runner, &request(), PASSWD, MOUNTS, &mut tx)
+            .map_err(|err| tx.rollback(&runner, err))
+            .unwrap_err();
+
+        assert!(matches!(
+            err,
+            Error::ProvisioningRolledBack { ref rollback_errors, .. } if rollback_errors.is_empty()
+        ));
+        assert_eq!(
+            runner.actions()[15..],
+            [
+                "rm /etc/ssh/sshd_config.d/mkwebuser-bob.conf",
+                "systemctl reload sshd",
+                "systemctl disable mnt-bob.mount",
+                "rm /etc/systemd/system/mnt-bob.mount",
+                "systemctl daemon-reload",
+                "umount /mnt/bob",
+                "rmdir /mnt/bob",
+                "losetup --detach /dev/loop0",
+                "rm /srv/provme/images/bob.img",
+                "userdel --remove bob",
+            ]
+        );
+    }
+}
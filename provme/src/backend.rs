@@ -0,0 +1,51 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Where the space of an account lives and how its quota is enforced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// An image file in the image store, formatted with ext4, xfs or btrfs
+    /// and mounted through a loop device.
+    #[default]
+    Image,
+    /// Per-user block and inode limits of the kernel quota facility on the
+    /// filesystem holding the home directory.
+    Quota,
+    /// Hard block and inode limits of an XFS project quota on the home
+    /// directory.
+    Xfs,
+    /// A btrfs subvolume at the mount point, limited with a qgroup.
+    Btrfs,
+    /// A logical volume carved from a volume group, formatted with ext4, xfs
+    /// or btrfs and mounted at the mount point.
+    Lvm,
+}
+
+impl FromStr for Backend {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "image" => Ok(Backend::Image),
+            "quota" => Ok(Backend::Quota),
+            "xfs" => Ok(Backend::Xfs),
+            "btrfs" => Ok(Backend::Btrfs),
+            "lvm" => Ok(Backend::Lvm),
+            _ => Err("expected `image`, `quota`, `xfs`, `btrfs` or `lvm`"),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::Image => write!(f, "image"),
+            Backend::Quota => write!(f, "quota"),
+            Backend::Xfs => write!(f, "xfs"),
+            Backend::Btrfs => write!(f, "btrfs"),
+            Backend::Lvm => write!(f, "lvm"),
+        }
+    }
+}
@@ -0,0 +1,343 @@
+use crate::{
+    containing_mount, Allocation, Backend, Error, Runner, Transaction, Usage, User, UserSpace,
+};
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Inode number of the root directory of every btrfs subvolume.
+const SUBVOLUME_ROOT_INODE: u64 = 256;
+
+/// Creates a btrfs subvolume for `user` at `<mount_base>/<username>`, which
+/// becomes the chroot directory, and limits it with a qgroup.
+///
+/// Qgroup limits require quotas on the filesystem (`btrfs quota enable`), so
+/// nothing is created unless `quotas_enabled` is set.
+pub fn create_btrfs_space(
+    runner: &dyn Runner,
+    user: &User,
+    quota_mb: u64,
+    mount_base: &str,
+    mounts: &str,
+    quotas_enabled: bool,
+    tx: &mut Transaction,
+) -> Result<UserSpace, Error> {
+    // Prepare arguments
+    let path = format!(
+        "{mount_base}/{user}",
+        mount_base = mount_base,
+        user = user.username
+    );
+    containing_mount(mounts, &path)
+        .filter(|mount| mount.filesystem == "btrfs")
+        .ok_or(Error::UserSpaceCreationFailed {
+            reason: "The mount base is not on btrfs",
+        })?;
+    if !quotas_enabled {
+        return Err(Error::UserSpaceCreationFailed {
+            reason: "Quotas are not enabled on the btrfs filesystem",
+        });
+    }
+
+    // Create subvolume, unless an earlier run did
+    if is_subvolume(&path) {
+        // Log
+        println!("Subvolume exists: {path}", path = path);
+    } else {
+        invoke_create_subvolume(runner, &path)?;
+        let undo_path = path.clone();
+        tx.on_rollback(format!("delete subvolume {}", path), move |runner| {
+            invoke_delete_subvolume(runner, &undo_path)
+        });
+
+        // Log
+        println!("Subvolume created: {path}", path = path);
+    }
+
+    // Limit subvolume
+    invoke_limit_subvolume(runner, &path, Some(quota_mb), |reason| {
+        Error::UserSpaceCreationFailed { reason }
+    })?;
+
+    // Log
+    println!(
+        "Qgroup limit set: {size}M ({path})",
+        size = quota_mb,
+        path = path,
+    );
+
+    // Instantiate data structure
+    Ok(UserSpace {
+        name: "subvolume".to_string(),
+        path: path.clone(),
+        size_mb: quota_mb,
+        allocation: Allocation::Sparse,
+        filesystem: "btrfs".to_string(),
+        format: None,
+        backend: Backend::Btrfs,
+        loop_device: None,
+        mount_point: path,
+    })
+}
+
+/// Changes the qgroup limit of the subvolume at `path`.
+pub fn resize_btrfs_space(runner: &dyn Runner, path: &str, quota_mb: u64) -> Result<(), Error> {
+    // Limit subvolume
+    invoke_limit_subvolume(runner, path, Some(quota_mb), |reason| {
+        Error::UserSpaceResizingFailed { reason }
+    })?;
+
+    // Log
+    println!(
+        "Qgroup limit set: {size}M ({path})",
+        size = quota_mb,
+        path = path,
+    );
+
+    Ok(())
+}
+
+/// Deletes the subvolume of `username` below `mount_base`, if present.
+pub fn delete_btrfs_space(
+    runner: &dyn Runner,
+    username: &str,
+    mount_base: &str,
+) -> Result<(), Error> {
+    // Prepare arguments
+    let path = format!(
+        "{mount_base}/{user}",
+        mount_base = mount_base,
+        user = username
+    );
+
+    // Delete subvolume
+    if !is_subvolume(&path) {
+        return Ok(());
+    }
+    invoke_delete_subvolume(runner, &path)?;
+
+    // Log
+    println!("Subvolume deleted: {path}", path = path);
+
+    Ok(())
+}
+
+/// Queries the referenced size and limit of the subvolume at `path` with
+/// `btrfs qgroup show`.
+pub fn btrfs_usage(path: &str) -> io::Result<Usage> {
+    let output = Command::new("btrfs")
+        .args(["qgroup", "show", "--raw", "-r", "-f", path])
+        .stderr(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other("btrfs qgroup error"));
+    }
+    parse_qgroup_usage(&String::from_utf8_lossy(&output.stdout))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unexpected btrfs qgroup output"))
+}
+
+/// Tells whether quotas are enabled on the btrfs filesystem holding `path`,
+/// which makes `btrfs qgroup show` succeed.
+pub fn btrfs_quotas_enabled(path: &str) -> bool {
+    Command::new("btrfs")
+        .args(["qgroup", "show", path])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// Reads the first qgroup of a `btrfs qgroup show --raw -r` listing.
+fn parse_qgroup_usage(listing: &str) -> Option<Usage> {
+    // Lines look like `0/257  16384  16384  1073741824`, after a header
+    let fields: Vec<&str> = listing
+        .lines()
+        .find(|line| line.starts_with("0/"))?
+        .split_whitespace()
+        .collect();
+    let referenced: u64 = fields.get(1)?.parse().ok()?;
+    let limit: u64 = fields.get(3)?.parse().ok()?;
+    let to_mb = |bytes: u64| bytes / (1024 * 1024);
+    Some(Usage {
+        total_mb: to_mb(limit),
+        used_mb: to_mb(referenced),
+        free_mb: to_mb(limit.saturating_sub(referenced)),
+    })
+}
+
+fn is_subvolume(path: &str) -> bool {
+    Path::new(path).is_dir()
+        && fs::metadata(path)
+            .map(|metadata| metadata.ino() == SUBVOLUME_ROOT_INODE)
+            .unwrap_or(false)
+}
+
+fn invoke_create_subvolume(runner: &dyn Runner, path: &str) -> Result<(), Error> {
+    let mut cmd = Command::new("btrfs");
+    cmd.args(["subvolume", "create"]);
+    cmd.arg(path);
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| Error::UserSpaceCreationFailed {
+            reason: "Unable to get exit status",
+        })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::UserSpaceCreationFailed {
+            reason: "btrfs subvolume error",
+        }
+        .with_output(&cmd, &output))
+    }
+}
+
+/// Sets the referenced size limit of the subvolume at `path`, or removes it
+/// if `quota_mb` is `None`. Failures are reported with `failed`.
+fn invoke_limit_subvolume(
+    runner: &dyn Runner,
+    path: &str,
+    quota_mb: Option<u64>,
+    failed: fn(&'static str) -> Error,
+) -> Result<(), Error> {
+    let limit = quota_mb.map_or("none".to_string(), |quota_mb| format!("{}M", quota_mb));
+    let mut cmd = Command::new("btrfs");
+    cmd.args(["qgroup", "limit"]);
+    cmd.arg(limit);
+    cmd.arg(path);
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| failed("Unable to get exit status"))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(failed("btrfs qgroup error").with_output(&cmd, &output))
+    }
+}
+
+fn invoke_delete_subvolume(runner: &dyn Runner, path: &str) -> Result<(), Error> {
+    let mut cmd = Command::new("btrfs");
+    cmd.args(["subvolume", "delete"]);
+    cmd.arg(path);
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| Error::UserSpaceDeletionFailed {
+            reason: "Unable to get exit status",
+        })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::UserSpaceDeletionFailed {
+            reason: "btrfs subvolume error",
+        }
+        .with_output(&cmd, &output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RecordingRunner;
+
+    const MOUNTS: &str = "/dev/sda1 / ext4 rw,relatime 0 0\n\
+                          /dev/sdb1 /mnt btrfs rw,relatime,compress=zstd 0 0\n";
+
+    fn user() -> User {
+        User {
+            username: "bob".to_string(),
+            base_directory: "/home".to_string(),
+            home_directory: "/home/bob".to_string(),
+        }
+    }
+
+    #[test]
+    fn create_btrfs_space_creates_and_limits_subvolume() {
+        let runner = RecordingRunner::new();
+        let mut tx = Transaction::new();
+
+        let userspace =
+            create_btrfs_space(&runner, &user(), 1024, "/mnt", MOUNTS, true, &mut tx).unwrap();
+
+        assert_eq!(userspace.path, "/mnt/bob");
+        assert_eq!(userspace.mount_point, "/mnt/bob");
+        assert_eq!(userspace.size_mb, 1024);
+        assert_eq!(userspace.backend, Backend::Btrfs);
+        assert_eq!(
+            runner.actions(),
+            vec![
+                "btrfs subvolume create /mnt/bob",
+                "btrfs qgroup limit 1024M /mnt/bob",
+            ]
+        );
+    }
+
+    #[test]
+    fn create_btrfs_space_rolls_back_subvolume() {
+        let runner = RecordingRunner::new().respond("btrfs qgroup", 1, "");
+        let mut tx = Transaction::new();
+
+        let err =
+            create_btrfs_space(&runner, &user(), 1024, "/mnt", MOUNTS, true, &mut tx).unwrap_err();
+        tx.rollback(&runner, err);
+
+        assert_eq!(runner.actions()[2], "btrfs subvolume delete /mnt/bob");
+    }
+
+    #[test]
+    fn create_btrfs_space_requires_btrfs() {
+        let runner = RecordingRunner::new();
+        let mut tx = Transaction::new();
+
+        let result = create_btrfs_space(
+            &runner,
+            &user(),
+            1024,
+            "/mnt",
+            "/dev/sda1 / ext4 rw 0 0\n",
+            true,
+            &mut tx,
+        );
+
+        assert!(matches!(
+            result,
+            Err(Error::UserSpaceCreationFailed {
+                reason: "The mount base is not on btrfs"
+            })
+        ));
+        assert!(runner.actions().is_empty());
+    }
+
+    #[test]
+    fn create_btrfs_space_requires_quotas() {
+        let runner = RecordingRunner::new();
+        let mut tx = Transaction::new();
+
+        let result = create_btrfs_space(&runner, &user(), 1024, "/mnt", MOUNTS, false, &mut tx);
+
+        assert!(matches!(
+            result,
+            Err(Error::UserSpaceCreationFailed {
+                reason: "Quotas are not enabled on the btrfs filesystem"
+            })
+        ));
+        assert!(runner.actions().is_empty());
+    }
+
+    #[test]
+    fn qgroup_usage_is_parsed() {
+        let listing = "qgroupid         rfer         excl     max_rfer \n\
+                       --------         ----         ----     -------- \n\
+                       0/257        104857600    104857600   1073741824 \n";
+
+        assert_eq!(
+            parse_qgroup_usage(listing),
+            Some(Usage {
+                total_mb: 1024,
+                used_mb: 100,
+                free_mb: 924,
+            })
+        );
+        assert_eq!(parse_qgroup_usage("0/257 16384 16384 none\n"), None);
+    }
+}
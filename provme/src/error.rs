@@ -0,0 +1,308 @@
+use crate::runner::render_command;
+use std::fmt;
+use std::io;
+use std::process::{Command, Output};
+
+#[derive(Debug)]
+pub enum Error {
+    UserCreationFailed {
+        reason: &'static str,
+    },
+    UserDeletionFailed {
+        reason: &'static str,
+    },
+    UserSpaceCreationFailed {
+        reason: &'static str,
+    },
+    UserSpaceFormattingFailed {
+        reason: &'static str,
+    },
+    UserSpaceMountingFailed {
+        reason: &'static str,
+    },
+    UserSpaceResizingFailed {
+        reason: &'static str,
+    },
+    UserSpaceUnmountingFailed {
+        reason: &'static str,
+    },
+    UserSpaceDeletionFailed {
+        reason: &'static str,
+    },
+    UserSpaceMigrationFailed {
+        reason: &'static str,
+    },
+    UserJailCreationFailed {
+        reason: &'static str,
+    },
+    UserJailDeletionFailed {
+        reason: &'static str,
+    },
+    SshKeysFailed {
+        reason: &'static str,
+    },
+    PasswordFailed {
+        reason: &'static str,
+    },
+    InventoryFailed {
+        reason: &'static str,
+    },
+    StatusFailed {
+        reason: &'static str,
+    },
+    ManifestFailed {
+        reason: &'static str,
+    },
+    ValidationFailed {
+        reason: &'static str,
+    },
+    /// An external command exited unsuccessfully while carrying out the
+    /// step that `error` describes.
+    CommandFailed {
+        error: Box<Error>,
+        command: String,
+        /// `None` if the command was terminated by a signal.
+        exit_code: Option<i32>,
+        stderr: String,
+    },
+    /// A file operation failed while carrying out the step that `error`
+    /// describes.
+    IoFailed {
+        error: Box<Error>,
+        source: io::Error,
+    },
+    ProvisioningRolledBack {
+        error: Box<Error>,
+        rollback_errors: Vec<Error>,
+    },
+}
+
+impl Error {
+    /// Attaches the command line, exit code and captured stderr of a
+    /// command that exited unsuccessfully.
+    pub(crate) fn with_output(self, cmd: &Command, output: &Output) -> Error {
+        Error::CommandFailed {
+            error: Box::new(self),
+            command: render_command(cmd),
+            exit_code: output.status.code(),
+            stderr: String::from_utf8_lossy(&output.stderr)
+                .trim_end()
+                .to_string(),
+        }
+    }
+
+    /// Attaches the I/O error a file operation failed with.
+    pub(crate) fn with_source(self, source: io::Error) -> Error {
+        Error::IoFailed {
+            error: Box::new(self),
+            source,
+        }
+    }
+
+    /// The step that failed, without the command or I/O details attached
+    /// to it.
+    pub fn failure(&self) -> &Error {
+        match self {
+            Error::CommandFailed { error, .. } | Error::IoFailed { error, .. } => error.failure(),
+            _ => self,
+        }
+    }
+
+    /// The documented process exit code of this failure class.
+    ///
+    /// | Code | Failure                                            |
+    /// |------|----------------------------------------------------|
+    /// | 10   | invalid username, path or option                   |
+    /// | 11   | invalid or unreadable manifest                     |
+    /// | 20   | creating or deleting the user                      |
+    /// | 30   | creating, formatting or mounting the user space    |
+    /// | 31   | resizing, migrating or removing the user space     |
+    /// | 40   | creating or removing the sshd configuration        |
+    /// | 41   | installing or changing SSH keys                    |
+    /// | 42   | setting or locking the password                    |
+    /// | 50   | reading or writing the inventory                   |
+    /// | 51   | reading the state of an account                    |
+    /// | 70   | a failed step could not be completely rolled back  |
+    ///
+    /// Failures that were rolled back completely keep the code of the step
+    /// that failed.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::ValidationFailed { .. } => 10,
+            Error::ManifestFailed { .. } => 11,
+            Error::UserCreationFailed { .. } | Error::UserDeletionFailed { .. } => 20,
+            Error::UserSpaceCreationFailed { .. }
+            | Error::UserSpaceFormattingFailed { .. }
+            | Error::UserSpaceMountingFailed { .. } => 30,
+            Error::UserSpaceResizingFailed { .. }
+            | Error::UserSpaceUnmountingFailed { .. }
+            | Error::UserSpaceDeletionFailed { .. }
+            | Error::UserSpaceMigrationFailed { .. } => 31,
+            Error::UserJailCreationFailed { .. } | Error::UserJailDeletionFailed { .. } => 40,
+            Error::SshKeysFailed { .. } => 41,
+            Error::PasswordFailed { .. } => 42,
+            Error::InventoryFailed { .. } => 50,
+            Error::StatusFailed { .. } => 51,
+            Error::CommandFailed { error, .. } | Error::IoFailed { error, .. } => error.exit_code(),
+            Error::ProvisioningRolledBack {
+                error,
+                rollback_errors,
+            } => {
+                if rollback_errors.is_empty() {
+                    error.exit_code()
+                } else {
+                    70
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UserCreationFailed { reason } => write!(f, "Unable to create user: {}", reason),
+            Error::UserDeletionFailed { reason } => write!(f, "Unable to delete user: {}", reason),
+            Error::UserSpaceCreationFailed { reason } => {
+                write!(f, "Unable to create user space: {}", reason)
+            }
+            Error::UserSpaceFormattingFailed { reason } => {
+                write!(f, "Unable to format user space: {}", reason)
+            }
+            Error::UserSpaceMountingFailed { reason } => {
+                write!(f, "Unable to mount user space: {}", reason)
+            }
+            Error::UserSpaceResizingFailed { reason } => {
+                write!(f, "Unable to resize user space: {}", reason)
+            }
+            Error::UserSpaceUnmountingFailed { reason } => {
+                write!(f, "Unable to unmount user space: {}", reason)
+            }
+            Error::UserSpaceDeletionFailed { reason } => {
+                write!(f, "Unable to delete user space: {}", reason)
+            }
+            Error::UserSpaceMigrationFailed { reason } => {
+                write!(f, "Unable to migrate user space: {}", reason)
+            }
+            Error::UserJailCreationFailed { reason } => {
+                write!(f, "Unable to create user jail: {}", reason)
+            }
+            Error::UserJailDeletionFailed { reason } => {
+                write!(f, "Unable to delete user jail: {}", reason)
+            }
+            Error::SshKeysFailed { reason } => write!(f, "Unable to update SSH keys: {}", reason),
+            Error::PasswordFailed { reason } => write!(f, "Unable to change password: {}", reason),
+            Error::InventoryFailed { reason } => write!(f, "Inventory error: {}", reason),
+            Error::StatusFailed { reason } => write!(f, "Unable to read status: {}", reason),
+            Error::ManifestFailed { reason } => write!(f, "Invalid manifest: {}", reason),
+            Error::ValidationFailed { reason } => write!(f, "Invalid request: {}", reason),
+            Error::CommandFailed {
+                error,
+                command,
+                exit_code,
+                stderr,
+            } => {
+                write!(f, "{}\n  command: {}", error, command)?;
+                match exit_code {
+                    Some(code) => write!(f, "\n  exit code: {}", code)?,
+                    None => write!(f, "\n  exit code: none (terminated by signal)")?,
+                }
+                for line in stderr.lines() {
+                    write!(f, "\n  stderr: {}", line)?;
+                }
+                Ok(())
+            }
+            Error::IoFailed { error, source } => write!(f, "{}: {}", error, source),
+            Error::ProvisioningRolledBack {
+                error,
+                rollback_errors,
+            } => {
+                write!(f, "{}", error)?;
+                if rollback_errors.is_empty() {
+                    write!(f, "\nAll changes were rolled back")
+                } else {
+                    write!(f, "\nUnable to roll back every change:")?;
+                    for err in rollback_errors {
+                        write!(f, "\n- {}", err)?;
+                    }
+                    Ok(())
+                }
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::CommandFailed { error, .. } | Error::ProvisioningRolledBack { error, .. } => {
+                Some(error.as_ref())
+            }
+            Error::IoFailed { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+
+    fn mkfs_failure() -> Error {
+        let mut cmd = Command::new("mkfs.ext4");
+        cmd.arg("/srv/provme/images/bob.img");
+        let output = Output {
+            status: ExitStatus::from_raw(1 << 8),
+            stdout: Vec::new(),
+            stderr: b"mkfs.ext4: No space left on device\n".to_vec(),
+        };
+        Error::UserSpaceFormattingFailed {
+            reason: "mkfs.ext4 error",
+        }
+        .with_output(&cmd, &output)
+    }
+
+    #[test]
+    fn command_failures_show_command_exit_code_and_stderr() {
+        assert_eq!(
+            mkfs_failure().to_string(),
+            "Unable to format user space: mkfs.ext4 error\n  \
+             command: mkfs.ext4 /srv/provme/images/bob.img\n  \
+             exit code: 1\n  \
+             stderr: mkfs.ext4: No space left on device"
+        );
+    }
+
+    #[test]
+    fn rollbacks_list_every_failed_undo_action() {
+        let error = Error::ProvisioningRolledBack {
+            error: Box::new(mkfs_failure()),
+            rollback_errors: vec![Error::UserDeletionFailed {
+                reason: "User currently logged in",
+            }],
+        };
+
+        assert!(error.to_string().ends_with(
+            "Unable to roll back every change:\n- Unable to delete user: User currently logged in"
+        ));
+        assert_eq!(error.exit_code(), 70);
+    }
+
+    #[test]
+    fn exit_codes_follow_the_failed_step() {
+        let rolled_back = Error::ProvisioningRolledBack {
+            error: Box::new(mkfs_failure()),
+            rollback_errors: Vec::new(),
+        };
+
+        assert_eq!(mkfs_failure().exit_code(), 30);
+        assert_eq!(rolled_back.exit_code(), 30);
+        assert_eq!(Error::ValidationFailed { reason: "test" }.exit_code(), 10);
+        assert!(matches!(
+            mkfs_failure().failure(),
+            Error::UserSpaceFormattingFailed { .. }
+        ));
+    }
+}
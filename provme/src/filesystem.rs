@@ -0,0 +1,196 @@
+use crate::Error;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::str::FromStr;
+
+/// Filesystem that image and LVM user spaces are formatted with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Filesystem {
+    #[default]
+    Ext4,
+    Xfs,
+    Btrfs,
+}
+
+impl Filesystem {
+    /// Byte offset and value of the magic number in the superblock.
+    fn magic(self) -> (u64, &'static [u8]) {
+        match self {
+            Filesystem::Ext4 => (1024 + 0x38, &[0x53, 0xef]),
+            Filesystem::Xfs => (0, b"XFSB"),
+            Filesystem::Btrfs => (0x1_0040, b"_BHRfS_M"),
+        }
+    }
+
+    /// Longest volume label the filesystem can store, in bytes.
+    pub fn max_label_len(self) -> usize {
+        match self {
+            Filesystem::Ext4 => 16,
+            Filesystem::Xfs => 12,
+            Filesystem::Btrfs => 255,
+        }
+    }
+}
+
+impl FromStr for Filesystem {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ext4" => Ok(Filesystem::Ext4),
+            "xfs" => Ok(Filesystem::Xfs),
+            "btrfs" => Ok(Filesystem::Btrfs),
+            _ => Err("expected `ext4`, `xfs` or `btrfs`"),
+        }
+    }
+}
+
+impl fmt::Display for Filesystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Filesystem::Ext4 => write!(f, "ext4"),
+            Filesystem::Xfs => write!(f, "xfs"),
+            Filesystem::Btrfs => write!(f, "btrfs"),
+        }
+    }
+}
+
+/// How a user space is formatted. Everything but the filesystem and label
+/// is only supported by ext4; unset options keep the mkfs defaults.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FormatOptions {
+    pub filesystem: Filesystem,
+    /// Percentage of blocks reserved for root (`mkfs.ext4 -m`).
+    pub reserved_percent: Option<u8>,
+    /// Bytes per inode (`mkfs.ext4 -i`).
+    pub inode_ratio: Option<u64>,
+    /// Number of inodes (`mkfs.ext4 -N`).
+    pub inode_count: Option<u64>,
+    pub label: Option<String>,
+    /// Comma-separated ext4 features to enable, or disable with `^`
+    /// (`mkfs.ext4 -O`).
+    pub features: Option<String>,
+}
+
+impl FormatOptions {
+    /// Checks that the options are supported by the filesystem and fit
+    /// together, so bad options are refused before anything is created.
+    pub fn validate(&self) -> Result<(), Error> {
+        let ext4_only = self.reserved_percent.is_some()
+            || self.inode_ratio.is_some()
+            || self.inode_count.is_some()
+            || self.features.is_some();
+        if ext4_only && self.filesystem != Filesystem::Ext4 {
+            return Err(Error::ValidationFailed {
+                reason: "Reserved blocks, inodes and features require ext4",
+            });
+        }
+        if self.reserved_percent.is_some_and(|percent| percent > 50) {
+            return Err(Error::ValidationFailed {
+                reason: "Reserved blocks exceed 50 percent",
+            });
+        }
+        if self.inode_ratio.is_some() && self.inode_count.is_some() {
+            return Err(Error::ValidationFailed {
+                reason: "Inode ratio and inode count are exclusive",
+            });
+        }
+        if let Some(label) = &self.label {
+            if label.len() > self.filesystem.max_label_len() {
+                return Err(Error::ValidationFailed {
+                    reason: "Label too long for the filesystem",
+                });
+            }
+        }
+        if let Some(features) = &self.features {
+            let valid_feature = |feature: &str| {
+                let name = feature.strip_prefix('^').unwrap_or(feature);
+                !name.is_empty()
+                    && name
+                        .chars()
+                        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+            };
+            if !features.split(',').all(valid_feature) {
+                return Err(Error::ValidationFailed {
+                    reason: "Features must be a comma-separated list of feature names",
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks whether the file or device at `path` starts with a superblock of
+/// `filesystem`.
+pub fn has_superblock(path: &str, filesystem: Filesystem) -> bool {
+    let (offset, expected) = filesystem.magic();
+    let mut magic = vec![0; expected.len()];
+    File::open(path)
+        .and_then(|mut file| {
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut magic)
+        })
+        .map(|_| magic == expected)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_options_are_checked_against_filesystem() {
+        let format = FormatOptions {
+            filesystem: Filesystem::Xfs,
+            reserved_percent: Some(0),
+            ..FormatOptions::default()
+        };
+
+        assert!(matches!(
+            format.validate(),
+            Err(Error::ValidationFailed {
+                reason: "Reserved blocks, inodes and features require ext4"
+            })
+        ));
+    }
+
+    #[test]
+    fn format_options_must_fit_together() {
+        let valid = FormatOptions {
+            reserved_percent: Some(0),
+            inode_ratio: Some(4096),
+            label: Some("bob".to_string()),
+            features: Some("^has_journal,metadata_csum".to_string()),
+            ..FormatOptions::default()
+        };
+        assert!(valid.validate().is_ok());
+
+        for format in &[
+            FormatOptions {
+                reserved_percent: Some(51),
+                ..valid.clone()
+            },
+            FormatOptions {
+                inode_count: Some(1024),
+                ..valid.clone()
+            },
+            FormatOptions {
+                label: Some("a".repeat(17)),
+                ..valid.clone()
+            },
+            FormatOptions {
+                features: Some("has_journal,,-E stride=1".to_string()),
+                ..valid.clone()
+            },
+        ] {
+            assert!(
+                matches!(format.validate(), Err(Error::ValidationFailed { .. })),
+                "{:?}",
+                format
+            );
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use crate::{Allocation, Backend, Error, FormatOptions, Runner, WebSpaceAccount};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Inventory entry describing one provisioned account.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccountRecord {
+    pub username: String,
+    pub home_directory: String,
+    /// Path of the image, or of the directory holding the space if the
+    /// backend does not use an image.
+    pub image_path: String,
+    pub size_mb: u64,
+    #[serde(default)]
+    pub allocation: Allocation,
+    #[serde(default)]
+    pub backend: Backend,
+    pub mount_point: String,
+    pub filesystem: String,
+    /// How the space was formatted, if provme formatted it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<FormatOptions>,
+    pub sshd_config_path: String,
+    /// Seconds since the Unix epoch.
+    pub created_at: u64,
+}
+
+impl AccountRecord {
+    pub fn new(acc: &WebSpaceAccount, created_at: u64) -> Self {
+        AccountRecord {
+            username: acc.user.username.clone(),
+            home_directory: acc.user.home_directory.clone(),
+            image_path: acc.userspace.path.clone(),
+            size_mb: acc.userspace.size_mb,
+            allocation: acc.userspace.allocation,
+            backend: acc.userspace.backend,
+            mount_point: acc.userspace.mount_point.clone(),
+            filesystem: acc.userspace.filesystem.clone(),
+            format: acc.userspace.format.clone(),
+            sshd_config_path: acc.jail.config_path.clone(),
+            created_at,
+        }
+    }
+}
+
+/// Persistent record of every account provisioned by provme, keyed by username.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Inventory {
+    pub accounts: BTreeMap<String, AccountRecord>,
+}
+
+impl Inventory {
+    /// Reads the inventory at `path`. A missing file is an empty inventory.
+    pub fn load(path: &str) -> Result<Inventory, Error> {
+        match fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json).map_err(|_| Error::InventoryFailed {
+                reason: "Unable to parse inventory",
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Inventory::default()),
+            Err(_) => Err(Error::InventoryFailed {
+                reason: "Unable to read inventory",
+            }),
+        }
+    }
+
+    /// Atomically replaces the inventory at `path`.
+    pub fn save(&self, runner: &dyn Runner, path: &str) -> Result<(), Error> {
+        let json = serde_json::to_string_pretty(self).map_err(|_| Error::InventoryFailed {
+            reason: "Unable to serialize inventory",
+        })?;
+        if let Some(directory) = Path::new(path).parent().and_then(|p| p.to_str()) {
+            runner
+                .create_dir_all(directory)
+                .map_err(|_| Error::InventoryFailed {
+                    reason: "Unable to create inventory directory",
+                })?;
+        }
+        runner
+            .replace_file(path, json + "\n")
+            .map_err(|_| Error::InventoryFailed {
+                reason: "Unable to write inventory",
+            })
+    }
+}
+
+/// Adds `acc` to the inventory at `path`, replacing any previous record but
+/// keeping its creation time.
+pub fn record_account(runner: &dyn Runner, path: &str, acc: &WebSpaceAccount) -> Result<(), Error> {
+    let mut inventory = Inventory::load(path)?;
+    let created_at = match inventory.accounts.get(&acc.user.username) {
+        Some(record) => record.created_at,
+        None => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+    };
+    inventory.accounts.insert(
+        acc.user.username.clone(),
+        AccountRecord::new(acc, created_at),
+    );
+    inventory.save(runner, path)?;
+
+    // Log
+    println!("Inventory updated: {path}", path = path);
+
+    Ok(())
+}
+
+/// Removes the record of `username` from the inventory at `path`, if any.
+pub fn forget_account(runner: &dyn Runner, path: &str, username: &str) -> Result<(), Error> {
+    let mut inventory = Inventory::load(path)?;
+    if inventory.accounts.remove(username).is_none() {
+        return Ok(());
+    }
+    inventory.save(runner, path)?;
+
+    // Log
+    println!("Inventory updated: {path}", path = path);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Backend, RecordingRunner, User, UserJail, UserSpace};
+
+    fn account() -> WebSpaceAccount {
+        WebSpaceAccount {
+            user: User {
+                username: "bob".to_string(),
+                base_directory: "/home".to_string(),
+                home_directory: "/home/bob".to_string(),
+            },
+            userspace: UserSpace {
+                name: "volume".to_string(),
+                path: "/home/bob/volume".to_string(),
+                size_mb: 16,
+                allocation: Allocation::Sparse,
+                filesystem: "ext4".to_string(),
+                format: None,
+                backend: Backend::Image,
+                loop_device: Some("/dev/loop4".to_string()),
+                mount_point: "/mnt/bob".to_string(),
+            },
+            jail: UserJail {
+                chroot_directory: "/mnt/bob".to_string(),
+                data_directory: "/mnt/bob/www".to_string(),
+                config_path: "/etc/ssh/sshd_config.d/mkwebuser-bob.conf".to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn record_account_writes_inventory_atomically() {
+        let runner = RecordingRunner::new();
+
+        record_account(&runner, "/nonexistent/provme/inventory.json", &account()).unwrap();
+
+        assert_eq!(
+            runner.actions(),
+            vec![
+                "mkdir --parents /nonexistent/provme",
+                "replace /nonexistent/provme/inventory.json",
+            ]
+        );
+        let json = runner.file("/nonexistent/provme/inventory.json").unwrap();
+        let inventory: Inventory = serde_json::from_str(&json).unwrap();
+        let record = &inventory.accounts["bob"];
+        assert_eq!(record.image_path, "/home/bob/volume");
+        assert_eq!(record.size_mb, 16);
+        assert_eq!(record.mount_point, "/mnt/bob");
+        assert_eq!(record.filesystem, "ext4");
+    }
+
+    #[test]
+    fn forget_account_skips_unknown_accounts() {
+        let runner = RecordingRunner::new();
+
+        forget_account(&runner, "/nonexistent/provme/inventory.json", "bob").unwrap();
+
+        assert!(runner.actions().is_empty());
+    }
+}
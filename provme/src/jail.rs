@@ -0,0 +1,399 @@
+use crate::{authorized_keys_path, Error, Runner, Transaction, User, UserSpace};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+#[derive(Debug)]
+pub struct UserJail {
+    pub chroot_directory: String,
+    pub data_directory: String,
+    pub config_path: String,
+}
+
+pub fn create_user_jail(
+    runner: &dyn Runner,
+    user: &User,
+    userspace: &UserSpace,
+    config_directory: &str,
+    keys_directory: &str,
+    password_authentication: bool,
+    tx: &mut Transaction,
+) -> Result<UserJail, Error> {
+    // Prepare arguments
+    let chroot_directory = userspace.mount_point.clone();
+    let data_directory = format!("{chroot}/www", chroot = chroot_directory);
+    let config_path = config_path(config_directory, &user.username);
+    let keys_path = authorized_keys_path(keys_directory, &user.username);
+
+    // Create writable data directory inside the root-owned chroot
+    invoke_create_data_directory(runner, &user.username, &data_directory)?;
+
+    // Log
+    println!("Data directory created: {path}", path = data_directory);
+
+    // Write and apply sshd configuration
+    let config = render_sshd_match_block(
+        &user.username,
+        &chroot_directory,
+        &keys_path,
+        password_authentication,
+    );
+    write_sshd_config(runner, &config_path, config, tx)?;
+
+    // Log
+    println!(
+        "Jail created: {chroot} ({config})",
+        chroot = chroot_directory,
+        config = config_path,
+    );
+
+    // Instantiate data structure
+    Ok(UserJail {
+        chroot_directory,
+        data_directory,
+        config_path,
+    })
+}
+
+/// Rewrites the sshd configuration of an existing jail to allow or refuse
+/// password authentication. Rolling back `tx` restores the previous
+/// configuration.
+pub fn update_user_jail(
+    runner: &dyn Runner,
+    username: &str,
+    chroot_directory: &str,
+    config_path: &str,
+    keys_path: &str,
+    password_authentication: bool,
+    tx: &mut Transaction,
+) -> Result<(), Error> {
+    // Write and apply sshd configuration
+    let config = render_sshd_match_block(
+        username,
+        chroot_directory,
+        keys_path,
+        password_authentication,
+    );
+    write_sshd_config(runner, config_path, config, tx)?;
+
+    // Log
+    println!(
+        "Jail updated: {chroot} ({config})",
+        chroot = chroot_directory,
+        config = config_path,
+    );
+
+    Ok(())
+}
+
+pub fn delete_user_jail(
+    runner: &dyn Runner,
+    username: &str,
+    config_directory: &str,
+) -> Result<(), Error> {
+    // Prepare arguments
+    let config_path = config_path(config_directory, username);
+
+    // Remove sshd configuration
+    if !Path::new(&config_path).exists() {
+        return Ok(());
+    }
+    invoke_delete_user_jail(runner, &config_path)?;
+
+    // Log
+    println!("Jail deleted: {config}", config = config_path);
+
+    Ok(())
+}
+
+fn config_path(config_directory: &str, username: &str) -> String {
+    format!(
+        "{config_dir}/mkwebuser-{user}.conf",
+        config_dir = config_directory,
+        user = username
+    )
+}
+
+/// Writes `config` to `config_path`, unless an earlier run did, then
+/// validates it and reloads sshd.
+fn write_sshd_config(
+    runner: &dyn Runner,
+    config_path: &str,
+    config: String,
+    tx: &mut Transaction,
+) -> Result<(), Error> {
+    let existing_config = fs::read_to_string(config_path).ok();
+    if existing_config.as_deref() != Some(config.as_str()) {
+        runner
+            .write_file(config_path, config)
+            .map_err(|_| Error::UserJailCreationFailed {
+                reason: "Unable to write sshd configuration",
+            })?;
+        let undo_config_path = config_path.to_string();
+        match existing_config {
+            Some(existing_config) => {
+                tx.on_rollback(format!("restore {}", config_path), move |runner| {
+                    runner
+                        .write_file(&undo_config_path, existing_config)
+                        .map_err(|_| Error::UserJailCreationFailed {
+                            reason: "Unable to write sshd configuration",
+                        })?;
+                    invoke_reload_sshd(runner)
+                })
+            }
+            None => tx.on_rollback(format!("delete {}", config_path), move |runner| {
+                invoke_delete_user_jail(runner, &undo_config_path)
+            }),
+        }
+    }
+
+    // Validate sshd configuration
+    invoke_validate_sshd_config(runner)?;
+
+    // Apply sshd configuration
+    invoke_reload_sshd(runner)
+}
+
+fn render_sshd_match_block(
+    username: &str,
+    chroot_directory: &str,
+    keys_path: &str,
+    password_authentication: bool,
+) -> String {
+    let mut block = String::new();
+    block.push_str("# Managed by mkwebuser. Do not edit.\n");
+    block.push_str(&format!("Match User {user}\n", user = username));
+    block.push_str(&format!(
+        "    ChrootDirectory {chroot}\n",
+        chroot = chroot_directory
+    ));
+    block.push_str("    ForceCommand internal-sftp -d /www\n");
+    block.push_str(&format!(
+        "    AuthorizedKeysFile {keys}\n",
+        keys = keys_path
+    ));
+    block.push_str(&format!(
+        "    PasswordAuthentication {enabled}\n",
+        enabled = if password_authentication { "yes" } else { "no" }
+    ));
+    block.push_str("    AllowAgentForwarding no\n");
+    block.push_str("    AllowStreamLocalForwarding no\n");
+    block.push_str("    AllowTcpForwarding no\n");
+    block.push_str("    PermitTTY no\n");
+    block.push_str("    PermitTunnel no\n");
+    block.push_str("    X11Forwarding no\n");
+    block
+}
+
+fn invoke_create_data_directory<U, P>(
+    runner: &dyn Runner,
+    username: &U,
+    path: &P,
+) -> Result<(), Error>
+where
+    U: AsRef<str>,
+    P: AsRef<str>,
+{
+    let username: &str = username.as_ref();
+    let path: &str = path.as_ref();
+    runner
+        .create_dir_all(path)
+        .map_err(|_| Error::UserJailCreationFailed {
+            reason: "Unable to create data directory",
+        })?;
+    let mut cmd = Command::new("chown");
+    cmd.arg(format!("{user}:{user}", user = username));
+    cmd.arg(path);
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| Error::UserJailCreationFailed {
+            reason: "Unable to get exit status",
+        })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::UserJailCreationFailed {
+            reason: "chown error",
+        }
+        .with_output(&cmd, &output))
+    }
+}
+
+fn invoke_validate_sshd_config(runner: &dyn Runner) -> Result<(), Error> {
+    let mut cmd = Command::new("sshd");
+    cmd.arg("-t"); // test mode: only check the configuration
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| Error::UserJailCreationFailed {
+            reason: "Unable to get exit status",
+        })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::UserJailCreationFailed {
+            reason: "sshd rejected the configuration",
+        }
+        .with_output(&cmd, &output))
+    }
+}
+
+fn invoke_delete_user_jail<P>(runner: &dyn Runner, config_path: &P) -> Result<(), Error>
+where
+    P: AsRef<str>,
+{
+    let config_path: &str = config_path.as_ref();
+    runner
+        .remove_file(config_path)
+        .map_err(|_| Error::UserJailDeletionFailed {
+            reason: "Unable to remove sshd configuration",
+        })?;
+    invoke_reload_sshd(runner)
+}
+
+fn invoke_reload_sshd(runner: &dyn Runner) -> Result<(), Error> {
+    let mut cmd = Command::new("systemctl");
+    cmd.args(["reload", "sshd"]);
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| Error::UserJailCreationFailed {
+            reason: "Unable to get exit status",
+        })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::UserJailCreationFailed {
+            reason: "Unable to reload sshd",
+        }
+        .with_output(&cmd, &output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Allocation, Backend, RecordingRunner};
+    use std::env;
+    use std::process;
+
+    fn user() -> User {
+        User {
+            username: "bob".to_string(),
+            base_directory: "/home".to_string(),
+            home_directory: "/home/bob".to_string(),
+        }
+    }
+
+    fn userspace() -> UserSpace {
+        UserSpace {
+            name: "volume".to_string(),
+            path: "/home/bob/volume".to_string(),
+            size_mb: 16,
+            allocation: Allocation::Sparse,
+            filesystem: "ext4".to_string(),
+            format: None,
+            backend: Backend::Image,
+            loop_device: Some("/dev/loop4".to_string()),
+            mount_point: "/mnt/bob".to_string(),
+        }
+    }
+
+    #[test]
+    fn create_user_jail_writes_validates_and_reloads() {
+        let runner = RecordingRunner::new();
+        let mut tx = Transaction::new();
+
+        let jail = create_user_jail(
+            &runner,
+            &user(),
+            &userspace(),
+            "/etc/ssh/d",
+            "/etc/ssh/keys",
+            false,
+            &mut tx,
+        )
+        .unwrap();
+
+        assert_eq!(jail.chroot_directory, "/mnt/bob");
+        assert_eq!(jail.data_directory, "/mnt/bob/www");
+        assert_eq!(jail.config_path, "/etc/ssh/d/mkwebuser-bob.conf");
+        assert_eq!(
+            runner.actions(),
+            vec![
+                "mkdir --parents /mnt/bob/www",
+                "chown bob:bob /mnt/bob/www",
+                "write /etc/ssh/d/mkwebuser-bob.conf",
+                "sshd -t",
+                "systemctl reload sshd",
+            ]
+        );
+        let config = runner.file("/etc/ssh/d/mkwebuser-bob.conf").unwrap();
+        assert!(config.contains("Match User bob\n"));
+        assert!(config.contains("    ChrootDirectory /mnt/bob\n"));
+        assert!(config.contains("    ForceCommand internal-sftp -d /www\n"));
+        assert!(config.contains("    AuthorizedKeysFile /etc/ssh/keys/bob\n"));
+        assert!(config.contains("    PasswordAuthentication no\n"));
+    }
+
+    #[test]
+    fn create_user_jail_does_not_reload_rejected_config() {
+        let runner = RecordingRunner::new().respond("sshd -t", 255, "");
+        let mut tx = Transaction::new();
+
+        let result = create_user_jail(
+            &runner,
+            &user(),
+            &userspace(),
+            "/etc/ssh/d",
+            "/etc/ssh/keys",
+            false,
+            &mut tx,
+        );
+
+        assert!(matches!(
+            result.as_ref().map_err(Error::failure),
+            Err(Error::UserJailCreationFailed {
+                reason: "sshd rejected the configuration"
+            })
+        ));
+        assert_eq!(runner.actions().last().unwrap(), "sshd -t");
+
+        tx.rollback(&runner, result.unwrap_err());
+        assert_eq!(runner.file("/etc/ssh/d/mkwebuser-bob.conf"), None);
+    }
+
+    #[test]
+    fn failed_update_restores_previous_config() {
+        let config_path = env::temp_dir().join(format!("provme-jail-{}.conf", process::id()));
+        let config_path = config_path.to_str().unwrap();
+        let previous = render_sshd_match_block("bob", "/mnt/bob", "/etc/ssh/keys/bob", false);
+        fs::write(config_path, &previous).unwrap();
+        let runner = RecordingRunner::new().respond("sshd -t", 255, "");
+        let mut tx = Transaction::new();
+
+        let result = update_user_jail(
+            &runner,
+            "bob",
+            "/mnt/bob",
+            config_path,
+            "/etc/ssh/keys/bob",
+            true,
+            &mut tx,
+        );
+        let updated = runner.file(config_path).unwrap();
+        tx.rollback(&runner, result.unwrap_err());
+        fs::remove_file(config_path).unwrap();
+
+        assert!(updated.contains("    PasswordAuthentication yes\n"));
+        assert_eq!(runner.file(config_path), Some(previous));
+        assert_eq!(runner.actions().last().unwrap(), "systemctl reload sshd");
+    }
+
+    #[test]
+    fn delete_user_jail_skips_missing_config() {
+        let runner = RecordingRunner::new();
+
+        delete_user_jail(&runner, "bob", "/nonexistent/sshd_config.d").unwrap();
+
+        assert!(runner.actions().is_empty());
+    }
+}
@@ -0,0 +1,432 @@
+use crate::{validate_layout, validate_username, Error, Inventory, Layout, Runner, Transaction};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Key types sshd accepts from authorized_keys files.
+const KEY_TYPES: &[&str] = &[
+    "ssh-ed25519",
+    "ssh-rsa",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "sk-ssh-ed25519@openssh.com",
+    "sk-ecdsa-sha2-nistp256@openssh.com",
+];
+
+/// An OpenSSH public key as written in authorized_keys files, without
+/// options.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SshKey {
+    pub key_type: String,
+    /// Base64-encoded key blob.
+    pub blob: String,
+    pub comment: Option<String>,
+}
+
+impl SshKey {
+    /// Checks whether `key`, a public key with or without comment, a key
+    /// blob or a comment, identifies this key.
+    pub fn matches(&self, key: &str) -> bool {
+        match key.parse::<SshKey>() {
+            Ok(other) => other.blob == self.blob,
+            Err(_) => key == self.blob || Some(key) == self.comment.as_deref(),
+        }
+    }
+}
+
+impl FromStr for SshKey {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split_whitespace();
+        let key_type = fields.next().ok_or("expected a public key")?;
+        if !KEY_TYPES.contains(&key_type) {
+            return Err("expected a key type such as `ssh-ed25519` or `ssh-rsa`");
+        }
+        let blob = fields.next().ok_or("missing key data")?;
+        let data = decode_base64(blob).ok_or("key data is not valid base64")?;
+        // The blob starts with the length-prefixed key type
+        if data.len() < 4
+            || data[..4] != (key_type.len() as u32).to_be_bytes()
+            || data.get(4..4 + key_type.len()) != Some(key_type.as_bytes())
+        {
+            return Err("key data does not match the key type");
+        }
+        let comment = fields.collect::<Vec<_>>().join(" ");
+        Ok(SshKey {
+            key_type: key_type.to_string(),
+            blob: blob.to_string(),
+            comment: Some(comment).filter(|comment| !comment.is_empty()),
+        })
+    }
+}
+
+impl fmt::Display for SshKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.key_type, self.blob)?;
+        if let Some(comment) = &self.comment {
+            write!(f, " {}", comment)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses every key in `keys`, the contents of an authorized_keys or public
+/// key file. Empty lines and comments are skipped.
+pub fn parse_ssh_keys(keys: &str) -> Result<Vec<SshKey>, &'static str> {
+    keys.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::parse)
+        .collect()
+}
+
+/// Reads every key in the public key file at `path`.
+pub fn load_ssh_keys(path: &str) -> Result<Vec<SshKey>, Error> {
+    let keys = fs::read_to_string(path).map_err(|_| Error::SshKeysFailed {
+        reason: "Unable to read key file",
+    })?;
+    parse_ssh_keys(&keys).map_err(|reason| Error::SshKeysFailed { reason })
+}
+
+/// Path of the authorized_keys file of `username`, outside of its chroot.
+pub fn authorized_keys_path(keys_directory: &str, username: &str) -> String {
+    format!(
+        "{keys_dir}/{user}",
+        keys_dir = keys_directory,
+        user = username
+    )
+}
+
+/// Installs `keys` as the authorized keys of `username`, replacing the
+/// keys an earlier run installed. Does nothing without keys.
+pub fn create_ssh_keys(
+    runner: &dyn Runner,
+    username: &str,
+    keys: &[SshKey],
+    keys_directory: &str,
+    tx: &mut Transaction,
+) -> Result<(), Error> {
+    if keys.is_empty() {
+        return Ok(());
+    }
+
+    // Prepare arguments
+    let keys_path = authorized_keys_path(keys_directory, username);
+
+    // Write authorized keys, unless an earlier run did
+    let contents = render_authorized_keys(keys);
+    let existing_contents = fs::read_to_string(&keys_path).ok();
+    if existing_contents.as_deref() != Some(contents.as_str()) {
+        runner
+            .create_dir_all(keys_directory)
+            .map_err(|_| Error::SshKeysFailed {
+                reason: "Unable to create authorized keys directory",
+            })?;
+        runner
+            .write_file(&keys_path, contents)
+            .map_err(|_| Error::SshKeysFailed {
+                reason: "Unable to write authorized keys",
+            })?;
+        let undo_keys_path = keys_path.clone();
+        match existing_contents {
+            Some(existing_contents) => {
+                tx.on_rollback(format!("restore {}", keys_path), move |runner| {
+                    runner
+                        .write_file(&undo_keys_path, existing_contents)
+                        .map_err(|_| Error::SshKeysFailed {
+                            reason: "Unable to write authorized keys",
+                        })
+                })
+            }
+            None => tx.on_rollback(format!("delete {}", keys_path), move |runner| {
+                invoke_delete_ssh_keys(runner, &undo_keys_path)
+            }),
+        }
+    }
+
+    // Log
+    println!(
+        "Keys installed: {count} ({path})",
+        count = keys.len(),
+        path = keys_path,
+    );
+
+    Ok(())
+}
+
+/// Removes the authorized keys of `username`, if there are any.
+pub fn delete_ssh_keys(
+    runner: &dyn Runner,
+    username: &str,
+    keys_directory: &str,
+) -> Result<(), Error> {
+    // Prepare arguments
+    let keys_path = authorized_keys_path(keys_directory, username);
+
+    // Remove authorized keys
+    if !Path::new(&keys_path).exists() {
+        return Ok(());
+    }
+    invoke_delete_ssh_keys(runner, &keys_path)?;
+
+    // Log
+    println!("Keys deleted: {path}", path = keys_path);
+
+    Ok(())
+}
+
+/// Lists the authorized keys of a provisioned account.
+pub fn list_ssh_keys(layout: &Layout, username: &str) -> Result<Vec<SshKey>, Error> {
+    let keys_path = account_keys_path(layout, username)?;
+    read_ssh_keys(&keys_path)
+}
+
+/// Authorizes `keys` for a provisioned account, in addition to its current
+/// keys. Keys it already has are skipped. Returns every key of the account.
+pub fn add_ssh_keys(
+    runner: &dyn Runner,
+    layout: &Layout,
+    username: &str,
+    keys: &[SshKey],
+) -> Result<Vec<SshKey>, Error> {
+    let keys_path = account_keys_path(layout, username)?;
+    let mut authorized_keys = read_ssh_keys(&keys_path)?;
+    for key in keys {
+        if !authorized_keys.iter().any(|k| k.blob == key.blob) {
+            authorized_keys.push(key.clone());
+        }
+    }
+    runner
+        .create_dir_all(&layout.authorized_keys_dir)
+        .map_err(|_| Error::SshKeysFailed {
+            reason: "Unable to create authorized keys directory",
+        })?;
+    write_ssh_keys(runner, &keys_path, &authorized_keys)?;
+    Ok(authorized_keys)
+}
+
+/// Revokes the keys of a provisioned account that `keys` identify by key,
+/// blob or comment. Returns the remaining keys.
+pub fn remove_ssh_keys(
+    runner: &dyn Runner,
+    layout: &Layout,
+    username: &str,
+    keys: &[String],
+) -> Result<Vec<SshKey>, Error> {
+    let keys_path = account_keys_path(layout, username)?;
+    let mut authorized_keys = read_ssh_keys(&keys_path)?;
+    for key in keys {
+        if !authorized_keys.iter().any(|k| k.matches(key)) {
+            return Err(Error::SshKeysFailed {
+                reason: "Key not found",
+            });
+        }
+        authorized_keys.retain(|k| !k.matches(key));
+    }
+    write_ssh_keys(runner, &keys_path, &authorized_keys)?;
+    Ok(authorized_keys)
+}
+
+/// Path of the authorized_keys file of an account recorded in the inventory.
+fn account_keys_path(layout: &Layout, username: &str) -> Result<String, Error> {
+    validate_username(username)?;
+    validate_layout(layout)?;
+    let inventory = Inventory::load(&layout.inventory_path)?;
+    if !inventory.accounts.contains_key(username) {
+        return Err(Error::SshKeysFailed {
+            reason: "Account not found in inventory",
+        });
+    }
+    Ok(authorized_keys_path(&layout.authorized_keys_dir, username))
+}
+
+/// Reads the keys in `keys_path`; a missing file holds no keys.
+fn read_ssh_keys(keys_path: &str) -> Result<Vec<SshKey>, Error> {
+    if !Path::new(keys_path).exists() {
+        return Ok(Vec::new());
+    }
+    let contents = fs::read_to_string(keys_path).map_err(|_| Error::SshKeysFailed {
+        reason: "Unable to read authorized keys",
+    })?;
+    parse_ssh_keys(&contents).map_err(|reason| Error::SshKeysFailed { reason })
+}
+
+fn write_ssh_keys(runner: &dyn Runner, keys_path: &str, keys: &[SshKey]) -> Result<(), Error> {
+    runner
+        .replace_file(keys_path, render_authorized_keys(keys))
+        .map_err(|_| Error::SshKeysFailed {
+            reason: "Unable to write authorized keys",
+        })
+}
+
+fn render_authorized_keys(keys: &[SshKey]) -> String {
+    let mut contents = String::new();
+    contents.push_str("# Managed by mkwebuser. Edit with `provme keys`.\n");
+    for key in keys {
+        contents.push_str(&format!("{key}\n", key = key));
+    }
+    contents
+}
+
+fn invoke_delete_ssh_keys<P>(runner: &dyn Runner, keys_path: &P) -> Result<(), Error>
+where
+    P: AsRef<str>,
+{
+    let keys_path: &str = keys_path.as_ref();
+    runner
+        .remove_file(keys_path)
+        .map_err(|_| Error::SshKeysFailed {
+            reason: "Unable to remove authorized keys",
+        })
+}
+
+/// Decodes padded standard base64, as used for key blobs.
+fn decode_base64(data: &str) -> Option<Vec<u8>> {
+    let data = data.as_bytes();
+    if data.is_empty() || !data.len().is_multiple_of(4) {
+        return None;
+    }
+    let chunks = data.len() / 4;
+    let mut bytes = Vec::with_capacity(chunks * 3);
+    for (n, chunk) in data.chunks(4).enumerate() {
+        let mut group = 0_u32;
+        let mut padding = 0;
+        for (i, &c) in chunk.iter().enumerate() {
+            let value = match c {
+                b'=' if i >= 2 && n == chunks - 1 => {
+                    padding += 1;
+                    0
+                }
+                _ if padding > 0 => return None,
+                b'A'..=b'Z' => c - b'A',
+                b'a'..=b'z' => c - b'a' + 26,
+                b'0'..=b'9' => c - b'0' + 52,
+                b'+' => 62,
+                b'/' => 63,
+                _ => return None,
+            };
+            group = group << 6 | u32::from(value);
+        }
+        bytes.extend_from_slice(&group.to_be_bytes()[1..4 - padding]);
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RecordingRunner;
+    use std::{env, process};
+
+    const ED25519: &str = "ssh-ed25519 \
+        AAAAC3NzaC1lZDI1NTE5AAAAIFtKicCZ0z78unAyAzklcWyA0HvC0tQ1pyAm/iDUyIFi bob@laptop";
+
+    #[test]
+    fn keys_are_parsed_and_printed_unchanged() {
+        let key: SshKey = ED25519.parse().unwrap();
+
+        assert_eq!(key.key_type, "ssh-ed25519");
+        assert_eq!(key.comment.as_deref(), Some("bob@laptop"));
+        assert_eq!(key.to_string(), ED25519);
+        assert!(key.matches("bob@laptop"));
+        assert!(key.matches(&ED25519[..80]));
+        assert!(!key.matches("alice@laptop"));
+    }
+
+    #[test]
+    fn malformed_keys_are_rejected() {
+        let blob = "AAAAC3NzaC1lZDI1NTE5AAAAIFtKicCZ0z78unAyAzklcWyA0HvC0tQ1pyAm/iDUyIFi";
+
+        assert!("".parse::<SshKey>().is_err());
+        assert!(format!("ssh-dss {}", blob).parse::<SshKey>().is_err());
+        assert!("ssh-ed25519".parse::<SshKey>().is_err());
+        assert!("ssh-ed25519 not*base64".parse::<SshKey>().is_err());
+        assert!(format!("ssh-rsa {}", blob).parse::<SshKey>().is_err());
+        assert!(format!("command=\"ls\" ssh-ed25519 {}", blob)
+            .parse::<SshKey>()
+            .is_err());
+    }
+
+    #[test]
+    fn key_files_skip_comments_and_empty_lines() {
+        let keys = parse_ssh_keys(&format!("# laptop\n\n{}\n", ED25519)).unwrap();
+
+        assert_eq!(keys.len(), 1);
+        assert!(parse_ssh_keys("ssh-ed25519 AAAA\n").is_err());
+    }
+
+    #[test]
+    fn base64_padding_is_checked() {
+        assert_eq!(decode_base64("Ym9i").unwrap(), b"bob");
+        assert_eq!(decode_base64("Ym8=").unwrap(), b"bo");
+        assert_eq!(decode_base64("Yg==").unwrap(), b"b");
+        assert_eq!(decode_base64("Yg==Ym9i"), None);
+        assert_eq!(decode_base64("Y=9i"), None);
+        assert_eq!(decode_base64("Ym9"), None);
+    }
+
+    #[test]
+    fn create_ssh_keys_writes_keys_and_rolls_back() {
+        let runner = RecordingRunner::new();
+        let mut tx = Transaction::new();
+        let keys = vec![ED25519.parse().unwrap()];
+
+        create_ssh_keys(&runner, "bob", &keys, "/etc/ssh/authorized_keys", &mut tx).unwrap();
+
+        let contents = runner.file("/etc/ssh/authorized_keys/bob").unwrap();
+        assert!(contents.ends_with(&format!("\n{}\n", ED25519)));
+
+        tx.rollback(&runner, Error::SshKeysFailed { reason: "test" });
+        assert_eq!(runner.file("/etc/ssh/authorized_keys/bob"), None);
+        assert_eq!(
+            runner.actions(),
+            vec![
+                "mkdir --parents /etc/ssh/authorized_keys",
+                "write /etc/ssh/authorized_keys/bob",
+                "rm /etc/ssh/authorized_keys/bob",
+            ]
+        );
+    }
+
+    #[test]
+    fn create_ssh_keys_restores_previous_keys() {
+        let keys_directory = env::temp_dir().join(format!("provme-keys-{}", process::id()));
+        fs::create_dir_all(&keys_directory).unwrap();
+        let keys_directory = keys_directory.to_str().unwrap();
+        let keys_path = authorized_keys_path(keys_directory, "bob");
+        fs::write(&keys_path, "ssh-ed25519 previous\n").unwrap();
+        let runner = RecordingRunner::new();
+        let mut tx = Transaction::new();
+        let keys = vec![ED25519.parse().unwrap()];
+
+        let result = create_ssh_keys(&runner, "bob", &keys, keys_directory, &mut tx);
+        fs::remove_dir_all(keys_directory).unwrap();
+        result.unwrap();
+        tx.rollback(&runner, Error::SshKeysFailed { reason: "test" });
+
+        assert_eq!(
+            runner.file(&keys_path).as_deref(),
+            Some("ssh-ed25519 previous\n")
+        );
+        assert_eq!(
+            runner.actions()[1..],
+            [
+                format!("write {}", keys_path),
+                format!("write {}", keys_path)
+            ]
+        );
+    }
+
+    #[test]
+    fn create_ssh_keys_skips_accounts_without_keys() {
+        let runner = RecordingRunner::new();
+        let mut tx = Transaction::new();
+
+        create_ssh_keys(&runner, "bob", &[], "/etc/ssh/authorized_keys", &mut tx).unwrap();
+
+        assert!(runner.actions().is_empty());
+    }
+}
@@ -0,0 +1,95 @@
+//! Provisioning of chroot-jailed sftp web space accounts.
+//!
+//! An account consists of a system user, a space limited by one of the
+//! storage backends, and an sshd `Match User` block jailing the user to it.
+//! Every change to the host goes through a [`Runner`], so callers can
+//! execute, print or record the provisioning steps.
+
+use std::path::{Path, PathBuf};
+
+mod account;
+mod backend;
+mod btrfs;
+mod error;
+mod filesystem;
+mod inventory;
+mod jail;
+mod keys;
+mod lvm;
+mod manifest;
+mod migrate;
+mod mount;
+mod password;
+mod quota;
+mod resize;
+mod runner;
+mod status;
+mod transaction;
+mod user;
+mod userspace;
+mod validate;
+mod xfs;
+
+pub use account::{
+    deprovision, provision, DeprovisionRequest, Layout, LayoutOptions, ProvisionRequest,
+    WebSpaceAccount,
+};
+pub use backend::Backend;
+pub use error::Error;
+pub use filesystem::{Filesystem, FormatOptions};
+pub use inventory::{AccountRecord, Inventory};
+pub use jail::UserJail;
+pub use keys::{
+    add_ssh_keys, list_ssh_keys, load_ssh_keys, parse_ssh_keys, remove_ssh_keys, SshKey,
+};
+pub use manifest::{apply, AccountSpec, Change, Manifest, Plan};
+pub use migrate::{migrate, MigrateRequest};
+pub use mount::remount_all;
+pub use password::{passwd, PasswdRequest, Password};
+pub use resize::{resize, ResizeRequest};
+pub use runner::{DryRunRunner, RecordingRunner, Runner, SystemRunner};
+pub use status::{list_accounts, AccountStatus, Usage};
+pub use user::User;
+pub use userspace::{Allocation, UserSpace};
+pub use validate::{validate_layout, validate_path, validate_username};
+
+use btrfs::{
+    btrfs_quotas_enabled, btrfs_usage, create_btrfs_space, delete_btrfs_space, resize_btrfs_space,
+};
+use filesystem::has_superblock;
+use inventory::{forget_account, record_account};
+use jail::{create_user_jail, delete_user_jail, update_user_jail};
+use keys::{authorized_keys_path, create_ssh_keys, delete_ssh_keys};
+use lvm::{create_lvm_space, delete_lvm_space, grow_lvm_space, shrink_lvm_space};
+use mount::{create_mount_unit, delete_mount_unit};
+use password::set_password;
+use quota::{
+    create_quota_space, delete_quota_space, jail_home_directory, release_home_directory,
+    resize_quota_space, BYTES_PER_INODE,
+};
+use status::space_usage;
+use transaction::Transaction;
+use user::{create_user, delete_user, foreign_user, provisioned_users, user_id};
+use userspace::{
+    containing_mount, create_mount_point, create_user_space, delete_user_space, grow_user_space,
+    image_path, invoke_check_filesystem, invoke_format_user_space, invoke_grow_filesystem,
+    invoke_mount_user_space, invoke_resize_filesystem, invoke_unmount_user_space, mount_source,
+    move_user_space, remove_mount_point, shrink_user_space,
+};
+use xfs::{create_xfs_space, delete_xfs_space, resize_xfs_space};
+
+/// Converts a command line path to a string, refusing paths that are not
+/// valid UTF-8 instead of mangling them.
+pub fn path_string(path: &Path) -> Result<String, Error> {
+    path.to_str()
+        .map(|s| s.to_string())
+        .ok_or(Error::ValidationFailed {
+            reason: "Path is not valid UTF-8",
+        })
+}
+
+/// Converts an optional command line path to a string, falling back to
+/// `default` if it is missing.
+fn path_or_default(path: &Option<PathBuf>, default: String) -> Result<String, Error> {
+    path.as_deref().map_or(Ok(default), path_string)
+}
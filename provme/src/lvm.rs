@@ -0,0 +1,533 @@
+use crate::{
+    create_mount_point, has_superblock, invoke_check_filesystem, invoke_format_user_space,
+    invoke_grow_filesystem, invoke_mount_user_space, invoke_resize_filesystem,
+    invoke_unmount_user_space, mount_source, remove_mount_point, Allocation, Backend, Error,
+    Filesystem, FormatOptions, Runner, Transaction, User, UserSpace,
+};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// Carves a logical volume named after `user` from `volume_group`, formats
+/// it and mounts it at `<mount_base>/<username>`.
+///
+/// Logical volumes are always fully allocated, whatever allocation the
+/// request asked for.
+#[allow(clippy::too_many_arguments)]
+pub fn create_lvm_space(
+    runner: &dyn Runner,
+    user: &User,
+    quota_mb: u64,
+    format: &FormatOptions,
+    volume_group: &str,
+    mount_base: &str,
+    mounts: &str,
+    tx: &mut Transaction,
+) -> Result<UserSpace, Error> {
+    // Prepare arguments
+    let path = volume_path(volume_group, &user.username);
+    let mount_point = format!(
+        "{mount_base}/{user}",
+        mount_base = mount_base,
+        user = user.username
+    );
+
+    // Refuse to take over a mount point used by another device
+    let mounted = mount_source(mounts, &mount_point);
+    if let Some(source) = &mounted {
+        if *source != path && *source != mapper_path(volume_group, &user.username) {
+            return Err(Error::UserSpaceMountingFailed {
+                reason: "Mount point is in use by another device",
+            });
+        }
+    }
+
+    // Create logical volume, unless an earlier run did
+    if Path::new(&path).exists() {
+        // Log
+        println!("Volume exists: {path}", path = path);
+    } else {
+        invoke_create_volume(runner, volume_group, &user.username, quota_mb)?;
+        let undo_path = path.clone();
+        tx.on_rollback(format!("remove {}", path), move |runner| {
+            invoke_remove_volume(runner, &undo_path)
+        });
+
+        // Log
+        println!(
+            "Volume created: {size}M ({path})",
+            size = quota_mb,
+            path = path,
+        );
+    }
+
+    // Format logical volume, unless it already holds a filesystem
+    let filesystem = format.filesystem;
+    if has_superblock(&path, filesystem) {
+        // Log
+        println!(
+            "Space formatted earlier: {filesystem} ({path})",
+            filesystem = filesystem,
+            path = path
+        );
+    } else {
+        invoke_format_user_space(runner, &path, format)?;
+
+        // Log
+        println!(
+            "Space formatted: {filesystem} ({path})",
+            filesystem = filesystem,
+            path = path
+        );
+    }
+
+    // Mount logical volume, unless an earlier run did
+    if mounted.is_some() {
+        // Log
+        println!(
+            "Space mounted earlier: {path} ({mount_point})",
+            path = path,
+            mount_point = mount_point,
+        );
+    } else {
+        create_mount_point(runner, &mount_point, tx)?;
+        invoke_mount_user_space(runner, &path, &mount_point, filesystem)?;
+        let undo_mount_point = mount_point.clone();
+        tx.on_rollback(format!("unmount {}", mount_point), move |runner| {
+            invoke_unmount_user_space(runner, &undo_mount_point)
+        });
+
+        // Log
+        println!(
+            "Space mounted: {path} ({mount_point})",
+            path = path,
+            mount_point = mount_point,
+        );
+    }
+
+    // Instantiate data structure
+    Ok(UserSpace {
+        name: user.username.clone(),
+        path,
+        size_mb: quota_mb,
+        allocation: Allocation::Preallocated,
+        filesystem: filesystem.to_string(),
+        format: Some(format.clone()),
+        backend: Backend::Lvm,
+        loop_device: None,
+        mount_point,
+    })
+}
+
+/// Unmounts and removes the logical volume of `username`, skipping whatever
+/// is already gone.
+pub fn delete_lvm_space(
+    runner: &dyn Runner,
+    username: &str,
+    volume_group: &str,
+    mount_base: &str,
+) -> Result<(), Error> {
+    // Prepare arguments
+    let path = volume_path(volume_group, username);
+    let mount_point = format!(
+        "{mount_base}/{user}",
+        mount_base = mount_base,
+        user = username
+    );
+    let mounts =
+        fs::read_to_string("/proc/self/mounts").map_err(|_| Error::UserSpaceUnmountingFailed {
+            reason: "Unable to read mount table",
+        })?;
+
+    // Unmount logical volume
+    if mount_source(&mounts, &mount_point).is_some() {
+        invoke_unmount_user_space(runner, &mount_point)?;
+
+        // Log
+        println!(
+            "Space unmounted: {path} ({mount_point})",
+            path = path,
+            mount_point = mount_point,
+        );
+    }
+    remove_mount_point(runner, &mount_point)?;
+
+    // Remove logical volume
+    if !Path::new(&path).exists() {
+        return Ok(());
+    }
+    invoke_remove_volume(runner, &path)?;
+
+    // Log
+    println!("Volume removed: {path}", path = path);
+
+    Ok(())
+}
+
+/// Grows the mounted logical volume at `path` to `quota_mb` and resizes its
+/// filesystem online.
+///
+/// If growing the filesystem fails, the volume stays enlarged; running the
+/// resize again completes it.
+pub fn grow_lvm_space(
+    runner: &dyn Runner,
+    path: &str,
+    filesystem: Filesystem,
+    mount_point: &str,
+    quota_mb: u64,
+    mounts: &str,
+) -> Result<(), Error> {
+    // Prepare arguments
+    if mount_source(mounts, mount_point).is_none() {
+        return Err(Error::UserSpaceResizingFailed {
+            reason: "User space is not mounted",
+        });
+    }
+
+    // Grow logical volume, then filesystem while mounted
+    invoke_resize_volume(runner, "lvextend", path, quota_mb)?;
+    invoke_grow_filesystem(runner, filesystem, &path, mount_point)?;
+
+    // Log
+    println!(
+        "Space resized: {size}M ({path})",
+        size = quota_mb,
+        path = path,
+    );
+
+    Ok(())
+}
+
+/// Shrinks the mounted ext4 logical volume at `path` to `quota_mb`.
+///
+/// The filesystem is unmounted, checked and shrunk before the volume, so it
+/// always fits; if any step fails, rolling back `tx` remounts it.
+pub fn shrink_lvm_space(
+    runner: &dyn Runner,
+    path: &str,
+    mount_point: &str,
+    quota_mb: u64,
+    used_mb: Option<u64>,
+    mounts: &str,
+    tx: &mut Transaction,
+) -> Result<(), Error> {
+    // Prepare arguments
+    if mount_source(mounts, mount_point).is_none() {
+        return Err(Error::UserSpaceResizingFailed {
+            reason: "User space is not mounted",
+        });
+    }
+
+    // Refuse to cut off data
+    let used_mb = used_mb.ok_or(Error::UserSpaceResizingFailed {
+        reason: "Unable to determine used space",
+    })?;
+    if used_mb >= quota_mb {
+        return Err(Error::UserSpaceResizingFailed {
+            reason: "Used space exceeds the new quota",
+        });
+    }
+
+    // Unmount logical volume
+    invoke_unmount_user_space(runner, &mount_point)?;
+    let undo_path = path.to_string();
+    let undo_mount_point = mount_point.to_string();
+    tx.on_rollback(format!("remount {}", mount_point), move |runner| {
+        invoke_mount_user_space(runner, &undo_path, &undo_mount_point, Filesystem::Ext4)
+    });
+
+    // Log
+    println!(
+        "Space unmounted: {path} ({mount_point})",
+        path = path,
+        mount_point = mount_point,
+    );
+
+    // Shrink filesystem, then logical volume
+    invoke_check_filesystem(runner, &path, true)?;
+    invoke_resize_filesystem(runner, &path, Some(quota_mb))?;
+    invoke_resize_volume(runner, "lvreduce", path, quota_mb)?;
+    invoke_check_filesystem(runner, &path, false)?;
+
+    // Remount logical volume
+    invoke_mount_user_space(runner, &path, &mount_point, Filesystem::Ext4)?;
+    let undo_mount_point = mount_point.to_string();
+    tx.on_rollback(format!("unmount {}", mount_point), move |runner| {
+        invoke_unmount_user_space(runner, &undo_mount_point)
+    });
+
+    // Log
+    println!(
+        "Space resized: {size}M ({path})",
+        size = quota_mb,
+        path = path,
+    );
+
+    Ok(())
+}
+
+fn volume_path(volume_group: &str, username: &str) -> String {
+    format!(
+        "/dev/{volume_group}/{user}",
+        volume_group = volume_group,
+        user = username
+    )
+}
+
+/// The device-mapper name the mount table shows for a logical volume, with
+/// hyphens in either name doubled.
+fn mapper_path(volume_group: &str, username: &str) -> String {
+    format!(
+        "/dev/mapper/{volume_group}-{user}",
+        volume_group = volume_group.replace('-', "--"),
+        user = username.replace('-', "--")
+    )
+}
+
+fn invoke_create_volume(
+    runner: &dyn Runner,
+    volume_group: &str,
+    name: &str,
+    quota_mb: u64,
+) -> Result<(), Error> {
+    let mut cmd = Command::new("lvcreate");
+    cmd.arg("--yes"); // wipe leftover signatures without asking
+    cmd.args(["--name", name]);
+    cmd.args(["--size", &format!("{}m", quota_mb)]);
+    cmd.arg(volume_group);
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| Error::UserSpaceCreationFailed {
+            reason: "Unable to get exit status",
+        })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::UserSpaceCreationFailed {
+            reason: "lvcreate error",
+        }
+        .with_output(&cmd, &output))
+    }
+}
+
+/// Sets the size of the logical volume at `path` with `lvextend` or
+/// `lvreduce`.
+fn invoke_resize_volume(
+    runner: &dyn Runner,
+    program: &str,
+    path: &str,
+    quota_mb: u64,
+) -> Result<(), Error> {
+    let mut cmd = Command::new(program);
+    cmd.arg("--yes"); // lvreduce asks before shrinking
+    cmd.args(["--size", &format!("{}m", quota_mb)]);
+    cmd.arg(path);
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| Error::UserSpaceResizingFailed {
+            reason: "Unable to get exit status",
+        })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::UserSpaceResizingFailed {
+            reason: "Unable to resize logical volume",
+        }
+        .with_output(&cmd, &output))
+    }
+}
+
+fn invoke_remove_volume(runner: &dyn Runner, path: &str) -> Result<(), Error> {
+    let mut cmd = Command::new("lvremove");
+    cmd.arg("--yes");
+    cmd.arg(path);
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| Error::UserSpaceDeletionFailed {
+            reason: "Unable to get exit status",
+        })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::UserSpaceDeletionFailed {
+            reason: "lvremove error",
+        }
+        .with_output(&cmd, &output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RecordingRunner;
+
+    const MOUNTS: &str = "/dev/mapper/provme-bob /mnt/bob ext4 rw,relatime 0 0\n";
+
+    fn user() -> User {
+        User {
+            username: "bob".to_string(),
+            base_directory: "/home".to_string(),
+            home_directory: "/home/bob".to_string(),
+        }
+    }
+
+    #[test]
+    fn create_lvm_space_creates_formats_and_mounts_volume() {
+        let runner = RecordingRunner::new();
+        let mut tx = Transaction::new();
+
+        let userspace = create_lvm_space(
+            &runner,
+            &user(),
+            1024,
+            &FormatOptions::default(),
+            "provme",
+            "/mnt",
+            "",
+            &mut tx,
+        )
+        .unwrap();
+
+        assert_eq!(userspace.path, "/dev/provme/bob");
+        assert_eq!(userspace.mount_point, "/mnt/bob");
+        assert_eq!(userspace.backend, Backend::Lvm);
+        assert_eq!(userspace.loop_device, None);
+        assert_eq!(
+            runner.actions(),
+            vec![
+                "lvcreate --yes --name bob --size 1024m provme",
+                "mkfs.ext4 /dev/provme/bob",
+                "mkdir --parents /mnt/bob",
+                "mount --types ext4 /dev/provme/bob /mnt/bob",
+            ]
+        );
+    }
+
+    #[test]
+    fn create_lvm_space_refuses_foreign_mounts() {
+        let runner = RecordingRunner::new();
+        let mut tx = Transaction::new();
+
+        let result = create_lvm_space(
+            &runner,
+            &user(),
+            1024,
+            &FormatOptions::default(),
+            "provme",
+            "/mnt",
+            "/dev/sdc1 /mnt/bob ext4 rw,relatime 0 0\n",
+            &mut tx,
+        );
+
+        assert!(matches!(
+            result,
+            Err(Error::UserSpaceMountingFailed {
+                reason: "Mount point is in use by another device"
+            })
+        ));
+        assert!(runner.actions().is_empty());
+        assert_eq!(
+            mapper_path("my-vg", "web-bob"),
+            "/dev/mapper/my--vg-web--bob"
+        );
+    }
+
+    #[test]
+    fn create_lvm_space_rolls_back_volume() {
+        let runner = RecordingRunner::new().respond("mount", 32, "");
+        let mut tx = Transaction::new();
+
+        let err = create_lvm_space(
+            &runner,
+            &user(),
+            1024,
+            &FormatOptions::default(),
+            "provme",
+            "/mnt",
+            "",
+            &mut tx,
+        )
+        .unwrap_err();
+        tx.rollback(&runner, err);
+
+        assert_eq!(
+            runner.actions().last().unwrap(),
+            "lvremove --yes /dev/provme/bob"
+        );
+    }
+
+    #[test]
+    fn grow_lvm_space_extends_volume_and_filesystem() {
+        let runner = RecordingRunner::new();
+
+        grow_lvm_space(
+            &runner,
+            "/dev/provme/bob",
+            Filesystem::Ext4,
+            "/mnt/bob",
+            2048,
+            MOUNTS,
+        )
+        .unwrap();
+
+        assert_eq!(
+            runner.actions(),
+            vec![
+                "lvextend --yes --size 2048m /dev/provme/bob",
+                "resize2fs /dev/provme/bob",
+            ]
+        );
+    }
+
+    #[test]
+    fn shrink_lvm_space_shrinks_filesystem_first() {
+        let runner = RecordingRunner::new();
+        let mut tx = Transaction::new();
+
+        shrink_lvm_space(
+            &runner,
+            "/dev/provme/bob",
+            "/mnt/bob",
+            512,
+            Some(100),
+            MOUNTS,
+            &mut tx,
+        )
+        .unwrap();
+
+        assert_eq!(
+            runner.actions(),
+            vec![
+                "umount /mnt/bob",
+                "e2fsck -f -p /dev/provme/bob",
+                "resize2fs /dev/provme/bob 512M",
+                "lvreduce --yes --size 512m /dev/provme/bob",
+                "e2fsck -f -n /dev/provme/bob",
+                "mount --types ext4 /dev/provme/bob /mnt/bob",
+            ]
+        );
+    }
+
+    #[test]
+    fn shrink_lvm_space_remounts_on_failure() {
+        let runner = RecordingRunner::new().respond("lvreduce", 5, "");
+        let mut tx = Transaction::new();
+
+        let err = shrink_lvm_space(
+            &runner,
+            "/dev/provme/bob",
+            "/mnt/bob",
+            512,
+            Some(100),
+            MOUNTS,
+            &mut tx,
+        )
+        .unwrap_err();
+        tx.rollback(&runner, err);
+
+        assert_eq!(
+            runner.actions()[4..],
+            ["mount --types ext4 /dev/provme/bob /mnt/bob",]
+        );
+    }
+}
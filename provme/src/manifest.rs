@@ -0,0 +1,531 @@
+use crate::{
+    deprovision, provision, resize, validate_layout, validate_username, AccountRecord, Allocation,
+    Backend, DeprovisionRequest, Error, Filesystem, Inventory, Layout, ProvisionRequest,
+    ResizeRequest, Runner,
+};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// Desired accounts of a host, read from a TOML manifest.
+///
+/// Top-level settings are defaults for every `[[account]]` table:
+///
+/// ```toml
+/// quota = 1024
+/// allocation = "sparse"
+///
+/// [[account]]
+/// username = "bob"
+/// quota = 2048
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub base: Option<String>,
+    pub mountbase: Option<String>,
+    pub quota: Option<u64>,
+    pub allocation: Option<Allocation>,
+    pub backend: Option<Backend>,
+    pub filesystem: Option<Filesystem>,
+    pub imagestore: Option<String>,
+    pub volumegroup: Option<String>,
+    #[serde(default, rename = "account")]
+    pub accounts: Vec<AccountSpec>,
+}
+
+/// A single `[[account]]` table of a [`Manifest`].
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccountSpec {
+    pub username: String,
+    pub base: Option<String>,
+    pub mountbase: Option<String>,
+    pub quota: Option<u64>,
+    pub allocation: Option<Allocation>,
+    pub backend: Option<Backend>,
+    pub filesystem: Option<Filesystem>,
+    pub imagestore: Option<String>,
+    pub volumegroup: Option<String>,
+    /// Public keys installed when the account is created.
+    #[serde(default)]
+    pub keys: Vec<String>,
+}
+
+impl Manifest {
+    /// Reads the manifest at `path`.
+    pub fn load(path: &str) -> Result<Manifest, Error> {
+        let toml = fs::read_to_string(path).map_err(|_| Error::ManifestFailed {
+            reason: "Unable to read manifest",
+        })?;
+        Manifest::parse(&toml)
+    }
+
+    pub fn parse(toml: &str) -> Result<Manifest, Error> {
+        toml::from_str(toml).map_err(|_| Error::ManifestFailed {
+            reason: "Unable to parse manifest",
+        })
+    }
+
+    /// Resolves every account to a provisioning request, falling back to the
+    /// manifest defaults, then to `layout` and [`ProvisionRequest::new`].
+    pub fn requests(&self, layout: &Layout) -> Result<Vec<ProvisionRequest>, Error> {
+        let mut usernames = BTreeSet::new();
+        let mut requests = Vec::new();
+        for spec in &self.accounts {
+            if !usernames.insert(spec.username.as_str()) {
+                return Err(Error::ManifestFailed {
+                    reason: "Account listed more than once",
+                });
+            }
+            validate_username(&spec.username)?;
+            let mut request = ProvisionRequest::new(&spec.username);
+            request.layout = layout.clone();
+            if let Some(base) = spec.base.as_ref().or(self.base.as_ref()) {
+                request.layout.base_directory = base.clone();
+            }
+            if let Some(mountbase) = spec.mountbase.as_ref().or(self.mountbase.as_ref()) {
+                request.layout.mount_base = mountbase.clone();
+            }
+            if let Some(imagestore) = spec.imagestore.as_ref().or(self.imagestore.as_ref()) {
+                request.layout.image_store = imagestore.clone();
+            }
+            if let Some(volumegroup) = spec.volumegroup.as_ref().or(self.volumegroup.as_ref()) {
+                request.layout.volume_group = volumegroup.clone();
+            }
+            if let Some(quota) = spec.quota.or(self.quota) {
+                request.quota_mb = quota;
+            }
+            if let Some(allocation) = spec.allocation.or(self.allocation) {
+                request.allocation = allocation;
+            }
+            if let Some(backend) = spec.backend.or(self.backend) {
+                request.backend = backend;
+            }
+            if let Some(filesystem) = spec.filesystem.or(self.filesystem) {
+                request.format.filesystem = filesystem;
+            }
+            request.ssh_keys = spec
+                .keys
+                .iter()
+                .map(|key| key.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|reason| Error::ManifestFailed { reason })?;
+            validate_layout(&request.layout)?;
+            requests.push(request);
+        }
+        Ok(requests)
+    }
+}
+
+/// A change needed to converge the host towards a manifest.
+#[derive(Debug)]
+pub enum Change {
+    Create(ProvisionRequest),
+    Resize {
+        username: String,
+        from_mb: u64,
+        to_mb: u64,
+    },
+    Remove(AccountRecord),
+}
+
+/// The changes [`apply`] makes, in order.
+#[derive(Debug, Default)]
+pub struct Plan {
+    pub changes: Vec<Change>,
+}
+
+impl Plan {
+    /// Compares the desired accounts with the inventory. Accounts missing from
+    /// `requests` are only removed if `prune` is set.
+    ///
+    /// Only quotas can be converged. An account whose backend or filesystem
+    /// differs from its inventory record is refused.
+    pub fn new(
+        requests: Vec<ProvisionRequest>,
+        inventory: &Inventory,
+        prune: bool,
+    ) -> Result<Plan, Error> {
+        let mut changes = Vec::new();
+        for request in &requests {
+            match inventory.accounts.get(&request.username) {
+                None => changes.push(Change::Create(request.clone())),
+                Some(record) => {
+                    check_drift(request, record)?;
+                    if record.size_mb != request.quota_mb {
+                        changes.push(Change::Resize {
+                            username: request.username.clone(),
+                            from_mb: record.size_mb,
+                            to_mb: request.quota_mb,
+                        });
+                    }
+                }
+            }
+        }
+        if prune {
+            for record in inventory.accounts.values() {
+                if !requests.iter().any(|r| r.username == record.username) {
+                    changes.push(Change::Remove(record.clone()));
+                }
+            }
+        }
+        Ok(Plan { changes })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for change in &self.changes {
+            match change {
+                Change::Create(request) => writeln!(
+                    f,
+                    "+ {user} ({size}M {allocation})",
+                    user = request.username,
+                    size = request.quota_mb,
+                    allocation = request.allocation,
+                )?,
+                Change::Resize {
+                    username,
+                    from_mb,
+                    to_mb,
+                } => writeln!(
+                    f,
+                    "~ {user} ({from}M -> {to}M)",
+                    user = username,
+                    from = from_mb,
+                    to = to_mb,
+                )?,
+                Change::Remove(record) => writeln!(
+                    f,
+                    "- {user} ({size}M)",
+                    user = record.username,
+                    size = record.size_mb,
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Refuses a request that would need an existing account to change its
+/// backend or filesystem.
+fn check_drift(request: &ProvisionRequest, record: &AccountRecord) -> Result<(), Error> {
+    if record.backend != request.backend {
+        // Log
+        println!(
+            "Backend differs: {user} ({from} -> {to})",
+            user = record.username,
+            from = record.backend,
+            to = request.backend,
+        );
+        return Err(Error::ManifestFailed {
+            reason: "Backend of an existing account cannot change",
+        });
+    }
+    let formatted = matches!(record.backend, Backend::Image | Backend::Lvm);
+    if formatted && record.filesystem != request.format.filesystem.to_string() {
+        // Log
+        println!(
+            "Filesystem differs: {user} ({from} -> {to})",
+            user = record.username,
+            from = record.filesystem,
+            to = request.format.filesystem,
+        );
+        return Err(Error::ManifestFailed {
+            reason: "Filesystem of an existing account cannot change",
+        });
+    }
+    Ok(())
+}
+
+/// Carries out every change of `plan`, stopping at the first failure.
+///
+/// Each change is applied on its own, so a failure leaves earlier changes
+/// in place and the plan can be recomputed and applied again.
+pub fn apply(runner: &dyn Runner, plan: &Plan, layout: &Layout) -> Result<(), Error> {
+    for change in &plan.changes {
+        match change {
+            Change::Create(request) => {
+                provision(runner, request)?;
+            }
+            Change::Resize {
+                username, to_mb, ..
+            } => {
+                let request = ResizeRequest {
+                    username: username.clone(),
+                    quota_mb: *to_mb,
+                    layout: layout.clone(),
+                };
+                resize(runner, &request)?;
+            }
+            Change::Remove(record) => {
+                let request = DeprovisionRequest {
+                    username: record.username.clone(),
+                    layout: record_layout(record, layout),
+                    archive_directory: None,
+                };
+                deprovision(runner, &request)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Recovers the layout an account was provisioned with from its record.
+fn record_layout(record: &AccountRecord, layout: &Layout) -> Layout {
+    let parent = |path: &str, default: &str| {
+        Path::new(path)
+            .parent()
+            .and_then(|p| p.to_str())
+            .unwrap_or(default)
+            .to_string()
+    };
+    Layout {
+        base_directory: parent(&record.home_directory, &layout.base_directory),
+        mount_base: parent(&record.mount_point, &layout.mount_base),
+        sshd_config_dir: parent(&record.sshd_config_path, &layout.sshd_config_dir),
+        inventory_path: layout.inventory_path.clone(),
+        image_store: match record.backend {
+            Backend::Image if record.image_path.ends_with(".img") => {
+                parent(&record.image_path, &layout.image_store)
+            }
+            _ => layout.image_store.clone(),
+        },
+        volume_group: match record.backend {
+            Backend::Lvm => parent(&record.image_path, "")
+                .rsplit('/')
+                .next()
+                .filter(|name| !name.is_empty())
+                .unwrap_or(&layout.volume_group)
+                .to_string(),
+            _ => layout.volume_group.clone(),
+        },
+        authorized_keys_dir: layout.authorized_keys_dir.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RecordingRunner;
+
+    const MANIFEST: &str = r#"
+quota = 512
+allocation = "sparse"
+
+[[account]]
+username = "alice"
+
+[[account]]
+username = "bob"
+quota = 2048
+base = "/srv/home"
+backend = "quota"
+"#;
+
+    fn record(username: &str, size_mb: u64) -> AccountRecord {
+        AccountRecord {
+            username: username.to_string(),
+            home_directory: format!("/srv/home/{}", username),
+            image_path: format!("/srv/home/{}/volume", username),
+            size_mb,
+            allocation: Allocation::Sparse,
+            backend: Backend::Image,
+            mount_point: format!("/mnt/{}", username),
+            filesystem: "ext4".to_string(),
+            format: None,
+            sshd_config_path: format!("/etc/ssh/sshd_config.d/mkwebuser-{}.conf", username),
+            created_at: 0,
+        }
+    }
+
+    fn layout() -> Layout {
+        Layout {
+            inventory_path: "/nonexistent/provme/inventory.json".to_string(),
+            ..Layout::default()
+        }
+    }
+
+    #[test]
+    fn requests_fall_back_to_manifest_defaults() {
+        let manifest = Manifest::parse(MANIFEST).unwrap();
+
+        let requests = manifest.requests(&layout()).unwrap();
+
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].username, "alice");
+        assert_eq!(requests[0].quota_mb, 512);
+        assert_eq!(requests[0].allocation, Allocation::Sparse);
+        assert_eq!(requests[0].layout.base_directory, "/home");
+        assert_eq!(requests[1].quota_mb, 2048);
+        assert_eq!(requests[1].layout.base_directory, "/srv/home");
+        assert_eq!(requests[1].layout.mount_base, "/mnt");
+        assert_eq!(requests[0].backend, Backend::Image);
+        assert_eq!(requests[1].backend, Backend::Quota);
+    }
+
+    #[test]
+    fn manifest_rejects_unknown_fields_and_duplicates() {
+        assert!(matches!(
+            Manifest::parse("[[account]]\nusername = \"bob\"\nqouta = 1\n"),
+            Err(Error::ManifestFailed {
+                reason: "Unable to parse manifest"
+            })
+        ));
+
+        let manifest =
+            Manifest::parse("[[account]]\nusername = \"bob\"\n[[account]]\nusername = \"bob\"\n")
+                .unwrap();
+
+        assert!(matches!(
+            manifest.requests(&layout()),
+            Err(Error::ManifestFailed {
+                reason: "Account listed more than once"
+            })
+        ));
+    }
+
+    #[test]
+    fn account_keys_are_validated() {
+        let manifest = Manifest::parse(
+            "[[account]]\nusername = \"bob\"\nkeys = [\"ssh-ed25519 \
+             AAAAC3NzaC1lZDI1NTE5AAAAIFtKicCZ0z78unAyAzklcWyA0HvC0tQ1pyAm/iDUyIFi bob@laptop\"]\n",
+        )
+        .unwrap();
+        let requests = manifest.requests(&layout()).unwrap();
+        assert_eq!(requests[0].ssh_keys.len(), 1);
+
+        let manifest =
+            Manifest::parse("[[account]]\nusername = \"bob\"\nkeys = [\"ssh-ed25519\"]\n").unwrap();
+        assert!(matches!(
+            manifest.requests(&layout()),
+            Err(Error::ManifestFailed {
+                reason: "missing key data"
+            })
+        ));
+    }
+
+    #[test]
+    fn plan_creates_resizes_and_prunes() {
+        let requests = Manifest::parse(MANIFEST)
+            .unwrap()
+            .requests(&layout())
+            .unwrap();
+        let mut inventory = Inventory::default();
+        inventory.accounts.insert(
+            "bob".to_string(),
+            AccountRecord {
+                backend: Backend::Quota,
+                ..record("bob", 1024)
+            },
+        );
+        inventory
+            .accounts
+            .insert("carol".to_string(), record("carol", 1024));
+
+        let plan = Plan::new(requests.clone(), &inventory, false).unwrap();
+        assert_eq!(
+            plan.to_string(),
+            "+ alice (512M sparse)\n~ bob (1024M -> 2048M)\n"
+        );
+
+        let plan = Plan::new(requests, &inventory, true).unwrap();
+        assert_eq!(
+            plan.to_string(),
+            "+ alice (512M sparse)\n~ bob (1024M -> 2048M)\n- carol (1024M)\n"
+        );
+    }
+
+    #[test]
+    fn plan_is_empty_once_converged() {
+        let requests = Manifest::parse("[[account]]\nusername = \"bob\"\nquota = 1024\n")
+            .unwrap()
+            .requests(&layout())
+            .unwrap();
+        let mut inventory = Inventory::default();
+        inventory
+            .accounts
+            .insert("bob".to_string(), record("bob", 1024));
+
+        assert!(Plan::new(requests, &inventory, true).unwrap().is_empty());
+    }
+
+    #[test]
+    fn plan_refuses_backend_and_filesystem_drift() {
+        let requests =
+            Manifest::parse("[[account]]\nusername = \"bob\"\nquota = 1024\nbackend = \"lvm\"\n")
+                .unwrap()
+                .requests(&layout())
+                .unwrap();
+        let mut inventory = Inventory::default();
+        inventory
+            .accounts
+            .insert("bob".to_string(), record("bob", 1024));
+
+        assert!(matches!(
+            Plan::new(requests, &inventory, false),
+            Err(Error::ManifestFailed {
+                reason: "Backend of an existing account cannot change"
+            })
+        ));
+
+        let requests = Manifest::parse(
+            "[[account]]\nusername = \"bob\"\nquota = 1024\nfilesystem = \"xfs\"\n",
+        )
+        .unwrap()
+        .requests(&layout())
+        .unwrap();
+
+        assert!(matches!(
+            Plan::new(requests, &inventory, false),
+            Err(Error::ManifestFailed {
+                reason: "Filesystem of an existing account cannot change"
+            })
+        ));
+    }
+
+    #[test]
+    fn apply_provisions_missing_accounts() {
+        let runner = RecordingRunner::new().respond("losetup --find", 0, "/dev/loop0\n");
+        let requests = Manifest::parse("[[account]]\nusername = \"alice\"\n")
+            .unwrap()
+            .requests(&layout())
+            .unwrap();
+        let plan = Plan::new(requests, &Inventory::default(), false).unwrap();
+
+        apply(&runner, &plan, &layout()).unwrap();
+
+        let actions = runner.actions();
+        assert!(actions[0].starts_with("useradd --base-dir /home"));
+        assert_eq!(
+            actions.last().unwrap(),
+            "replace /nonexistent/provme/inventory.json"
+        );
+    }
+
+    #[test]
+    fn removed_accounts_keep_their_layout() {
+        let layout = record_layout(&record("carol", 1024), &layout());
+
+        assert_eq!(layout.base_directory, "/srv/home");
+        assert_eq!(layout.mount_base, "/mnt");
+        assert_eq!(layout.sshd_config_dir, "/etc/ssh/sshd_config.d");
+        assert_eq!(layout.inventory_path, "/nonexistent/provme/inventory.json");
+    }
+
+    #[test]
+    fn removed_lvm_accounts_keep_their_volume_group() {
+        let mut record = record("carol", 1024);
+        record.backend = Backend::Lvm;
+        record.image_path = "/dev/storage/carol".to_string();
+
+        assert_eq!(record_layout(&record, &layout()).volume_group, "storage");
+    }
+}
@@ -0,0 +1,84 @@
+use crate::{
+    create_mount_unit, image_path, move_user_space, AccountRecord, Backend, Error, Filesystem,
+    Inventory, Layout, Runner, Transaction,
+};
+use std::fs;
+
+/// Everything needed to move images into the image store.
+#[derive(Debug, Clone)]
+pub struct MigrateRequest {
+    /// Only migrate this account instead of every image account.
+    pub username: Option<String>,
+    pub layout: Layout,
+}
+
+/// Moves the images of image accounts recorded in the inventory into the
+/// image store of the layout, points their mount units at the new paths
+/// and records them.
+///
+/// Accounts are migrated one at a time; if a step fails, that account is
+/// moved back and remounted, and accounts migrated before stay in the
+/// store. Returns the records of the migrated accounts.
+pub fn migrate(runner: &dyn Runner, request: &MigrateRequest) -> Result<Vec<AccountRecord>, Error> {
+    let layout = &request.layout;
+    let mut inventory = Inventory::load(&layout.inventory_path)?;
+    let mounts =
+        fs::read_to_string("/proc/self/mounts").map_err(|_| Error::UserSpaceMigrationFailed {
+            reason: "Unable to read mount table",
+        })?;
+    let usernames: Vec<String> = match &request.username {
+        Some(username) if !inventory.accounts.contains_key(username) => {
+            return Err(Error::UserSpaceMigrationFailed {
+                reason: "Account not found in inventory",
+            });
+        }
+        Some(username) => vec![username.clone()],
+        None => inventory.accounts.keys().cloned().collect(),
+    };
+
+    let mut migrated = Vec::new();
+    for username in usernames {
+        let record = match inventory.accounts.get_mut(&username) {
+            Some(record) if record.backend == Backend::Image => record,
+            _ => continue,
+        };
+        let new_path = image_path(&layout.image_store, &username);
+        if record.image_path == new_path {
+            continue;
+        }
+        let filesystem: Filesystem =
+            record
+                .filesystem
+                .parse()
+                .map_err(|_| Error::UserSpaceMigrationFailed {
+                    reason: "Unsupported filesystem",
+                })?;
+
+        let mut tx = Transaction::new();
+        move_user_space(
+            runner,
+            &record.image_path,
+            &new_path,
+            &layout.image_store,
+            filesystem,
+            &record.mount_point,
+            &mounts,
+            &mut tx,
+        )
+        .and_then(|_| {
+            create_mount_unit(
+                runner,
+                &new_path,
+                &record.mount_point,
+                &record.filesystem,
+                true,
+                &mut tx,
+            )
+        })
+        .map_err(|err| tx.rollback(runner, err))?;
+        record.image_path = new_path;
+        migrated.push(record.clone());
+        inventory.save(runner, &layout.inventory_path)?;
+    }
+    Ok(migrated)
+}
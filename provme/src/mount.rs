@@ -0,0 +1,398 @@
+use crate::{mount_source, AccountRecord, Backend, Error, Inventory, Layout, Runner, Transaction};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// Directory that systemd loads units of the administrator from.
+const UNIT_DIRECTORY: &str = "/etc/systemd/system";
+
+/// Writes and enables a systemd mount unit that mounts `device` at
+/// `mount_point` on boot. Images are mounted through a loop device.
+///
+/// If the unit already exists with other contents, it is rewritten and
+/// restored when `tx` is rolled back.
+pub fn create_mount_unit(
+    runner: &dyn Runner,
+    device: &str,
+    mount_point: &str,
+    filesystem: &str,
+    image: bool,
+    tx: &mut Transaction,
+) -> Result<(), Error> {
+    // Prepare arguments
+    let unit = unit_name(mount_point);
+    let unit_path = unit_path(&unit);
+
+    // Write mount unit, unless an earlier run did
+    let config = render_mount_unit(device, mount_point, filesystem, image);
+    let existing_config = fs::read_to_string(&unit_path).ok();
+    if existing_config.as_deref() != Some(config.as_str()) {
+        runner
+            .write_file(&unit_path, config)
+            .map_err(|_| Error::UserSpaceMountingFailed {
+                reason: "Unable to write mount unit",
+            })?;
+        let undo_unit_path = unit_path.clone();
+        let undo_config = existing_config.clone();
+        tx.on_rollback(format!("restore {}", unit_path), move |runner| {
+            match undo_config {
+                Some(config) => runner.write_file(&undo_unit_path, config).map_err(|_| {
+                    Error::UserSpaceMountingFailed {
+                        reason: "Unable to write mount unit",
+                    }
+                })?,
+                None => runner.remove_file(&undo_unit_path).map_err(|_| {
+                    Error::UserSpaceUnmountingFailed {
+                        reason: "Unable to remove mount unit",
+                    }
+                })?,
+            }
+            invoke_reload_systemd(runner)
+        });
+        invoke_reload_systemd(runner)?;
+    }
+
+    // Mount user space on boot
+    invoke_enable_mount_unit(runner, &unit)?;
+    if existing_config.is_none() {
+        let undo_unit = unit.clone();
+        tx.on_rollback(format!("disable {}", unit), move |runner| {
+            invoke_disable_mount_unit(runner, &undo_unit)
+        });
+    }
+
+    // Log
+    println!(
+        "Mount unit enabled: {unit} ({path})",
+        unit = unit,
+        path = unit_path,
+    );
+
+    Ok(())
+}
+
+/// Disables and removes the mount unit of `mount_point`, leaving the user
+/// space mounted. Does nothing if there is no such unit.
+pub fn delete_mount_unit(runner: &dyn Runner, mount_point: &str) -> Result<(), Error> {
+    // Prepare arguments
+    let unit = unit_name(mount_point);
+    let unit_path = unit_path(&unit);
+
+    // Remove mount unit
+    if !Path::new(&unit_path).exists() {
+        return Ok(());
+    }
+    invoke_disable_mount_unit(runner, &unit)?;
+    runner
+        .remove_file(&unit_path)
+        .map_err(|_| Error::UserSpaceUnmountingFailed {
+            reason: "Unable to remove mount unit",
+        })?;
+    invoke_reload_systemd(runner)?;
+
+    // Log
+    println!(
+        "Mount unit removed: {unit} ({path})",
+        unit = unit,
+        path = unit_path,
+    );
+
+    Ok(())
+}
+
+/// Mounts the user spaces of every image and LVM account in the inventory
+/// that is not mounted, for example after a mount unit failed on boot.
+/// Mount units that are missing, such as those of accounts provisioned
+/// before mount units existed, are created first.
+///
+/// Returns the names of the remounted accounts. Stops at the first account
+/// that cannot be mounted; accounts remounted before stay mounted.
+pub fn remount_all(runner: &dyn Runner, layout: &Layout) -> Result<Vec<String>, Error> {
+    let inventory = Inventory::load(&layout.inventory_path)?;
+    let mounts =
+        fs::read_to_string("/proc/self/mounts").map_err(|_| Error::UserSpaceMountingFailed {
+            reason: "Unable to read mount table",
+        })?;
+
+    let mut remounted = Vec::new();
+    for record in inventory.accounts.values() {
+        let mut tx = Transaction::new();
+        if remount_account(runner, record, &mounts, &mut tx)
+            .map_err(|err| tx.rollback(runner, err))?
+        {
+            remounted.push(record.username.clone());
+        }
+    }
+    Ok(remounted)
+}
+
+/// Makes sure the mount unit of `record` exists and starts it unless
+/// `mounts` shows the user space mounted. Returns whether it was started.
+fn remount_account(
+    runner: &dyn Runner,
+    record: &AccountRecord,
+    mounts: &str,
+    tx: &mut Transaction,
+) -> Result<bool, Error> {
+    let image = match record.backend {
+        Backend::Image => true,
+        Backend::Lvm => false,
+        _ => return Ok(false),
+    };
+    create_mount_unit(
+        runner,
+        &record.image_path,
+        &record.mount_point,
+        &record.filesystem,
+        image,
+        tx,
+    )?;
+    if mount_source(mounts, &record.mount_point).is_some() {
+        return Ok(false);
+    }
+    invoke_start_mount_unit(runner, &unit_name(&record.mount_point))?;
+
+    // Log
+    println!(
+        "Space mounted: {path} ({mount_point})",
+        path = record.image_path,
+        mount_point = record.mount_point,
+    );
+
+    Ok(true)
+}
+
+/// Name of the unit mounting `mount_point`, escaped the way
+/// `systemd-escape --path --suffix=mount` does.
+fn unit_name(mount_point: &str) -> String {
+    let path = mount_point.trim_matches('/');
+    if path.is_empty() {
+        return "-.mount".to_string();
+    }
+    let mut name = String::new();
+    for (i, byte) in path.bytes().enumerate() {
+        match byte {
+            b'/' => name.push('-'),
+            b'.' if i == 0 => name.push_str("\\x2e"),
+            b'.' | b'_' => name.push(byte as char),
+            _ if byte.is_ascii_alphanumeric() => name.push(byte as char),
+            _ => name.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    name.push_str(".mount");
+    name
+}
+
+fn unit_path(unit: &str) -> String {
+    format!("{unit_dir}/{unit}", unit_dir = UNIT_DIRECTORY, unit = unit)
+}
+
+fn render_mount_unit(device: &str, mount_point: &str, filesystem: &str, image: bool) -> String {
+    let mut unit = String::new();
+    unit.push_str("# Managed by mkwebuser. Do not edit.\n");
+    unit.push_str("[Unit]\n");
+    unit.push_str(&format!(
+        "Description=Web space {mount_point}\n",
+        mount_point = mount_point
+    ));
+    unit.push('\n');
+    unit.push_str("[Mount]\n");
+    unit.push_str(&format!("What={device}\n", device = device));
+    unit.push_str(&format!("Where={mount_point}\n", mount_point = mount_point));
+    unit.push_str(&format!("Type={filesystem}\n", filesystem = filesystem));
+    if image {
+        unit.push_str("Options=loop\n");
+    }
+    unit.push('\n');
+    unit.push_str("[Install]\n");
+    unit.push_str("WantedBy=multi-user.target\n");
+    unit
+}
+
+fn invoke_reload_systemd(runner: &dyn Runner) -> Result<(), Error> {
+    let mut cmd = Command::new("systemctl");
+    cmd.arg("daemon-reload");
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| Error::UserSpaceMountingFailed {
+            reason: "Unable to get exit status",
+        })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::UserSpaceMountingFailed {
+            reason: "Unable to reload systemd",
+        }
+        .with_output(&cmd, &output))
+    }
+}
+
+fn invoke_enable_mount_unit(runner: &dyn Runner, unit: &str) -> Result<(), Error> {
+    let mut cmd = Command::new("systemctl");
+    cmd.arg("enable");
+    cmd.arg(unit);
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| Error::UserSpaceMountingFailed {
+            reason: "Unable to get exit status",
+        })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::UserSpaceMountingFailed {
+            reason: "Unable to enable mount unit",
+        }
+        .with_output(&cmd, &output))
+    }
+}
+
+fn invoke_start_mount_unit(runner: &dyn Runner, unit: &str) -> Result<(), Error> {
+    let mut cmd = Command::new("systemctl");
+    cmd.arg("start");
+    cmd.arg(unit);
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| Error::UserSpaceMountingFailed {
+            reason: "Unable to get exit status",
+        })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::UserSpaceMountingFailed {
+            reason: "Unable to start mount unit",
+        }
+        .with_output(&cmd, &output))
+    }
+}
+
+fn invoke_disable_mount_unit(runner: &dyn Runner, unit: &str) -> Result<(), Error> {
+    let mut cmd = Command::new("systemctl");
+    cmd.arg("disable");
+    cmd.arg(unit);
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| Error::UserSpaceUnmountingFailed {
+            reason: "Unable to get exit status",
+        })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::UserSpaceUnmountingFailed {
+            reason: "Unable to disable mount unit",
+        }
+        .with_output(&cmd, &output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Allocation, RecordingRunner};
+
+    fn record() -> AccountRecord {
+        AccountRecord {
+            username: "bob".to_string(),
+            home_directory: "/home/bob".to_string(),
+            image_path: "/srv/provme/images/bob.img".to_string(),
+            size_mb: 1024,
+            allocation: Allocation::Preallocated,
+            backend: Backend::Image,
+            mount_point: "/mnt/web-bob".to_string(),
+            filesystem: "ext4".to_string(),
+            format: None,
+            sshd_config_path: "/etc/ssh/sshd_config.d/mkwebuser-web-bob.conf".to_string(),
+            created_at: 0,
+        }
+    }
+
+    #[test]
+    fn unit_names_are_escaped_paths() {
+        assert_eq!(unit_name("/mnt/bob"), "mnt-bob.mount");
+        assert_eq!(unit_name("/srv/web/bob/"), "srv-web-bob.mount");
+        assert_eq!(unit_name("/mnt/web-bob"), "mnt-web\\x2dbob.mount");
+        assert_eq!(unit_name("/.hidden/a.b"), "\\x2ehidden-a.b.mount");
+        assert_eq!(unit_name("/"), "-.mount");
+    }
+
+    #[test]
+    fn create_mount_unit_writes_enables_and_rolls_back() {
+        let runner = RecordingRunner::new();
+        let mut tx = Transaction::new();
+
+        create_mount_unit(
+            &runner,
+            "/srv/provme/images/bob.img",
+            "/mnt/bob",
+            "ext4",
+            true,
+            &mut tx,
+        )
+        .unwrap();
+
+        let unit = runner.file("/etc/systemd/system/mnt-bob.mount").unwrap();
+        assert!(unit.contains("What=/srv/provme/images/bob.img\n"));
+        assert!(unit.contains("Where=/mnt/bob\n"));
+        assert!(unit.contains("Type=ext4\n"));
+        assert!(unit.contains("Options=loop\n"));
+        assert!(unit.contains("WantedBy=multi-user.target\n"));
+
+        tx.rollback(&runner, Error::UserSpaceMountingFailed { reason: "test" });
+        assert_eq!(
+            runner.actions(),
+            vec![
+                "write /etc/systemd/system/mnt-bob.mount",
+                "systemctl daemon-reload",
+                "systemctl enable mnt-bob.mount",
+                "systemctl disable mnt-bob.mount",
+                "rm /etc/systemd/system/mnt-bob.mount",
+                "systemctl daemon-reload",
+            ]
+        );
+    }
+
+    #[test]
+    fn logical_volumes_are_mounted_without_loop_device() {
+        let unit = render_mount_unit("/dev/provme/bob", "/mnt/bob", "xfs", false);
+
+        assert!(unit.contains("What=/dev/provme/bob\n"));
+        assert!(unit.contains("Type=xfs\n"));
+        assert!(!unit.contains("Options="));
+    }
+
+    #[test]
+    fn delete_mount_unit_skips_missing_unit() {
+        let runner = RecordingRunner::new();
+
+        delete_mount_unit(&runner, "/nonexistent/bob").unwrap();
+
+        assert!(runner.actions().is_empty());
+    }
+
+    #[test]
+    fn remount_starts_units_of_unmounted_spaces() {
+        let runner = RecordingRunner::new();
+        let mut tx = Transaction::new();
+
+        let mounted = "/dev/loop2 /mnt/web-bob ext4 rw,relatime 0 0\n";
+        assert!(!remount_account(&runner, &record(), mounted, &mut tx).unwrap());
+        assert!(remount_account(&runner, &record(), "", &mut tx).unwrap());
+
+        assert_eq!(
+            runner.actions().last().unwrap(),
+            "systemctl start mnt-web\\x2dbob.mount"
+        );
+    }
+
+    #[test]
+    fn remount_skips_accounts_without_mounts() {
+        let runner = RecordingRunner::new();
+        let mut tx = Transaction::new();
+        let record = AccountRecord {
+            backend: Backend::Quota,
+            ..record()
+        };
+
+        assert!(!remount_account(&runner, &record, "", &mut tx).unwrap());
+        assert!(runner.actions().is_empty());
+    }
+}
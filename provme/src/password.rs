@@ -0,0 +1,361 @@
+use crate::{
+    authorized_keys_path, update_user_jail, validate_layout, validate_username, Error, Inventory,
+    Layout, Runner, Transaction,
+};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, Read};
+use std::process::Command;
+
+/// Characters of generated passwords; easy to type in any sftp client.
+const PASSWORD_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+/// Length of generated passwords, about 119 bits of entropy.
+const PASSWORD_LENGTH: usize = 20;
+/// Shortest password accepted from the user.
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// A login password. Its `Debug` output hides the password, so requests
+/// holding one can be logged.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Password(String);
+
+impl Password {
+    /// Checks that `password` can be set with chpasswd.
+    pub fn new(password: &str) -> Result<Password, Error> {
+        if password.chars().count() < MIN_PASSWORD_LENGTH {
+            return Err(Error::PasswordFailed {
+                reason: "Password shorter than 8 characters",
+            });
+        }
+        if password.contains(|c: char| c.is_control()) {
+            return Err(Error::PasswordFailed {
+                reason: "Password contains control characters",
+            });
+        }
+        Ok(Password(password.to_string()))
+    }
+
+    /// Generates a random password from the kernel random number generator.
+    pub fn generate() -> Result<Password, Error> {
+        let mut random = File::open("/dev/urandom").map_err(|_| Error::PasswordFailed {
+            reason: "Unable to open /dev/urandom",
+        })?;
+        let mut password = String::with_capacity(PASSWORD_LENGTH);
+        let mut byte = [0_u8];
+        while password.len() < PASSWORD_LENGTH {
+            random
+                .read_exact(&mut byte)
+                .map_err(|_| Error::PasswordFailed {
+                    reason: "Unable to read /dev/urandom",
+                })?;
+            // Reject bytes beyond the last full multiple of the alphabet,
+            // so every character is equally likely
+            let limit = 256 - 256 % PASSWORD_ALPHABET.len();
+            if usize::from(byte[0]) < limit {
+                password.push(char::from(
+                    PASSWORD_ALPHABET[usize::from(byte[0]) % PASSWORD_ALPHABET.len()],
+                ));
+            }
+        }
+        Ok(Password(password))
+    }
+
+    /// Reads a password from the first line of `input`, e.g. stdin.
+    pub fn read(input: &mut dyn BufRead) -> Result<Password, Error> {
+        let mut line = String::new();
+        input
+            .read_line(&mut line)
+            .map_err(|_| Error::PasswordFailed {
+                reason: "Unable to read password",
+            })?;
+        Password::new(line.trim_end_matches(['\r', '\n']))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Password(<hidden>)")
+    }
+}
+
+/// Everything needed to change the password of a provisioned account.
+#[derive(Debug, Clone)]
+pub struct PasswdRequest {
+    pub username: String,
+    /// New password, or `None` to lock the password and refuse password
+    /// authentication.
+    pub password: Option<Password>,
+    pub layout: Layout,
+}
+
+/// Sets or locks the password of an account recorded in the inventory and
+/// allows or refuses password authentication in its sshd configuration.
+///
+/// The sshd configuration is updated first and restored if any step fails.
+/// Setting or locking the password cannot be undone, so it runs last.
+/// If it fails, the password is left unchanged.
+pub fn passwd(runner: &dyn Runner, request: &PasswdRequest) -> Result<(), Error> {
+    validate_username(&request.username)?;
+    validate_layout(&request.layout)?;
+    let inventory = Inventory::load(&request.layout.inventory_path)?;
+    let record = inventory
+        .accounts
+        .get(&request.username)
+        .ok_or(Error::PasswordFailed {
+            reason: "Account not found in inventory",
+        })?;
+    let keys_path = authorized_keys_path(&request.layout.authorized_keys_dir, &record.username);
+
+    let mut tx = Transaction::new();
+    match &request.password {
+        Some(password) => update_user_jail(
+            runner,
+            &record.username,
+            &record.mount_point,
+            &record.sshd_config_path,
+            &keys_path,
+            true,
+            &mut tx,
+        )
+        .and_then(|_| set_password(runner, &record.username, password)),
+        None => update_user_jail(
+            runner,
+            &record.username,
+            &record.mount_point,
+            &record.sshd_config_path,
+            &keys_path,
+            false,
+            &mut tx,
+        )
+        .and_then(|_| lock_password(runner, &record.username)),
+    }
+    .map_err(|err| tx.rollback(runner, err))
+}
+
+/// Sets the password of `username`, hashed by chpasswd with the method
+/// configured in `/etc/login.defs`.
+pub fn set_password(runner: &dyn Runner, username: &str, password: &Password) -> Result<(), Error> {
+    invoke_change_password(runner, username, password)?;
+
+    // Log
+    println!("Password set: {user}", user = username);
+
+    Ok(())
+}
+
+fn lock_password(runner: &dyn Runner, username: &str) -> Result<(), Error> {
+    invoke_lock_password(runner, username)?;
+
+    // Log
+    println!("Password locked: {user}", user = username);
+
+    Ok(())
+}
+
+fn invoke_change_password(
+    runner: &dyn Runner,
+    username: &str,
+    password: &Password,
+) -> Result<(), Error> {
+    let mut cmd = Command::new("chpasswd");
+    // The password is passed on stdin, so it never shows up in the process list
+    let input = format!(
+        "{user}:{password}\n",
+        user = username,
+        password = password.as_str()
+    );
+    let output = runner
+        .output_with_input(&mut cmd, &input)
+        .map_err(|_| Error::PasswordFailed {
+            reason: "Unable to get exit status",
+        })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::PasswordFailed {
+            reason: "chpasswd error",
+        }
+        .with_output(&cmd, &output))
+    }
+}
+
+fn invoke_lock_password(runner: &dyn Runner, username: &str) -> Result<(), Error> {
+    let mut cmd = Command::new("usermod");
+    cmd.arg("--lock");
+    cmd.arg(username);
+    let output = runner.output(&mut cmd).map_err(|_| Error::PasswordFailed {
+        reason: "Unable to get exit status",
+    })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::PasswordFailed {
+            reason: "usermod error",
+        }
+        .with_output(&cmd, &output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AccountRecord, Allocation, Backend, RecordingRunner};
+    use std::{env, fs, process};
+
+    /// A layout in a fresh directory whose inventory holds an image account
+    /// `bob`, together with the path of its sshd configuration.
+    fn layout(name: &str) -> (Layout, String) {
+        let directory = env::temp_dir().join(format!("provme-{}-{}", name, process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let directory = directory.to_str().unwrap();
+        let config_path = format!("{}/mkwebuser-bob.conf", directory);
+        let layout = Layout {
+            inventory_path: format!("{}/inventory.json", directory),
+            sshd_config_dir: directory.to_string(),
+            ..Layout::default()
+        };
+        let mut inventory = Inventory::default();
+        inventory.accounts.insert(
+            "bob".to_string(),
+            AccountRecord {
+                username: "bob".to_string(),
+                home_directory: "/home/bob".to_string(),
+                image_path: "/srv/provme/images/bob.img".to_string(),
+                size_mb: 1024,
+                allocation: Allocation::Preallocated,
+                backend: Backend::Image,
+                mount_point: "/mnt/bob".to_string(),
+                filesystem: "ext4".to_string(),
+                format: None,
+                sshd_config_path: config_path.clone(),
+                created_at: 0,
+            },
+        );
+        fs::write(
+            &layout.inventory_path,
+            serde_json::to_string(&inventory).unwrap(),
+        )
+        .unwrap();
+        (layout, config_path)
+    }
+
+    #[test]
+    fn generated_passwords_are_long_and_random() {
+        let first = Password::generate().unwrap();
+        let second = Password::generate().unwrap();
+
+        assert_eq!(first.as_str().len(), PASSWORD_LENGTH);
+        assert!(first
+            .as_str()
+            .bytes()
+            .all(|b| PASSWORD_ALPHABET.contains(&b)));
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn passwords_are_read_from_the_first_line() {
+        let password = Password::read(&mut &b"correct horse\r\nignored\n"[..]).unwrap();
+        assert_eq!(password.as_str(), "correct horse");
+
+        assert!(matches!(
+            Password::read(&mut &b"short\n"[..]),
+            Err(Error::PasswordFailed {
+                reason: "Password shorter than 8 characters"
+            })
+        ));
+        assert!(Password::new("tab\tseparated").is_err());
+    }
+
+    #[test]
+    fn passwords_are_hidden() {
+        let password = Password::new("correct horse").unwrap();
+        let runner = RecordingRunner::new();
+
+        set_password(&runner, "bob", &password).unwrap();
+
+        assert_eq!(format!("{:?}", password), "Password(<hidden>)");
+        assert_eq!(runner.actions(), vec!["chpasswd < <hidden input>"]);
+    }
+
+    #[test]
+    fn failed_sshd_update_keeps_previous_password() {
+        let (layout, config_path) = layout("passwd-sshd");
+        let runner = RecordingRunner::new().respond("sshd -t", 255, "");
+        let request = PasswdRequest {
+            username: "bob".to_string(),
+            password: Some(Password::new("correct horse").unwrap()),
+            layout,
+        };
+
+        let result = passwd(&runner, &request);
+        fs::remove_dir_all(&request.layout.sshd_config_dir).unwrap();
+
+        assert!(matches!(
+            result,
+            Err(Error::ProvisioningRolledBack { ref rollback_errors, .. }) if rollback_errors.is_empty()
+        ));
+        assert_eq!(
+            runner.actions(),
+            vec![
+                format!("write {}", config_path),
+                "sshd -t".to_string(),
+                format!("rm {}", config_path),
+                "systemctl reload sshd".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_chpasswd_restores_sshd_configuration() {
+        let (layout, config_path) = layout("passwd-chpasswd");
+        let runner = RecordingRunner::new().fail("chpasswd", 1, "");
+        let request = PasswdRequest {
+            username: "bob".to_string(),
+            password: Some(Password::new("correct horse").unwrap()),
+            layout,
+        };
+
+        let result = passwd(&runner, &request);
+        fs::remove_dir_all(&request.layout.sshd_config_dir).unwrap();
+
+        assert!(matches!(
+            result,
+            Err(Error::ProvisioningRolledBack { ref rollback_errors, .. }) if rollback_errors.is_empty()
+        ));
+        assert_eq!(
+            runner.actions()[3..],
+            [
+                "chpasswd < <hidden input>".to_string(),
+                format!("rm {}", config_path),
+                "systemctl reload sshd".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_chpasswd_is_reported() {
+        let runner = RecordingRunner::new().fail(
+            "chpasswd",
+            1,
+            "chpasswd: (user bob) pam_chauthtok() failed\n",
+        );
+        let password = Password::new("correct horse").unwrap();
+
+        let err = set_password(&runner, "bob", &password).unwrap_err();
+
+        assert!(matches!(
+            err.failure(),
+            Error::PasswordFailed {
+                reason: "chpasswd error"
+            }
+        ));
+        assert!(!err.to_string().contains("correct horse"));
+        assert!(err
+            .to_string()
+            .ends_with("stderr: chpasswd: (user bob) pam_chauthtok() failed"));
+    }
+}
@@ -0,0 +1,337 @@
+use crate::{containing_mount, Allocation, Backend, Error, Runner, Transaction, User, UserSpace};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// Bytes per inode that mkfs.ext4 uses by default, so quota accounts get as
+/// many inodes as an image of the same size.
+pub const BYTES_PER_INODE: u64 = 16 * 1024;
+
+/// Limits the space of `user` with kernel user quotas on the filesystem
+/// holding its home directory, which also becomes the chroot directory.
+pub fn create_quota_space(
+    runner: &dyn Runner,
+    user: &User,
+    quota_mb: u64,
+    mounts: &str,
+    tx: &mut Transaction,
+) -> Result<UserSpace, Error> {
+    // Prepare arguments
+    let home_directory = &user.home_directory;
+    let mount = containing_mount(mounts, home_directory).ok_or(Error::UserSpaceCreationFailed {
+        reason: "No filesystem holds the home directory",
+    })?;
+
+    // Hand the home directory to root
+    jail_home_directory(runner, home_directory)?;
+    let undo_username = user.username.clone();
+    let undo_home_directory = home_directory.clone();
+    tx.on_rollback(format!("release {}", home_directory), move |runner| {
+        release_home_directory(runner, &undo_username, &undo_home_directory)
+    });
+
+    // Set block and inode limits
+    invoke_set_quota(
+        runner,
+        &user.username,
+        Some(quota_mb),
+        &mount.target,
+        |reason| Error::UserSpaceCreationFailed { reason },
+    )?;
+    let undo_username = user.username.clone();
+    let undo_target = mount.target.clone();
+    tx.on_rollback(format!("clear quota of {}", user.username), move |runner| {
+        invoke_set_quota(runner, &undo_username, None, &undo_target, |reason| {
+            Error::UserSpaceCreationFailed { reason }
+        })
+    });
+
+    // Log
+    println!(
+        "Quota set: {size}M ({user} on {target})",
+        size = quota_mb,
+        user = user.username,
+        target = mount.target,
+    );
+
+    // Instantiate data structure
+    Ok(UserSpace {
+        name: "home".to_string(),
+        path: home_directory.clone(),
+        size_mb: quota_mb,
+        allocation: Allocation::Sparse,
+        filesystem: mount.filesystem,
+        format: None,
+        backend: Backend::Quota,
+        loop_device: None,
+        mount_point: home_directory.clone(),
+    })
+}
+
+/// Changes the limits of a quota account.
+pub fn resize_quota_space(
+    runner: &dyn Runner,
+    username: &str,
+    home_directory: &str,
+    quota_mb: u64,
+    mounts: &str,
+) -> Result<(), Error> {
+    // Prepare arguments
+    let mount = containing_mount(mounts, home_directory).ok_or(Error::UserSpaceResizingFailed {
+        reason: "No filesystem holds the home directory",
+    })?;
+
+    // Set block and inode limits
+    invoke_set_quota(runner, username, Some(quota_mb), &mount.target, |reason| {
+        Error::UserSpaceResizingFailed { reason }
+    })?;
+
+    // Log
+    println!(
+        "Quota set: {size}M ({user} on {target})",
+        size = quota_mb,
+        user = username,
+        target = mount.target,
+    );
+
+    Ok(())
+}
+
+/// Clears the limits of a quota account and returns its home directory to
+/// the user, so `userdel --remove` deletes it.
+pub fn delete_quota_space(
+    runner: &dyn Runner,
+    username: &str,
+    home_directory: &str,
+) -> Result<(), Error> {
+    // Prepare arguments
+    if !Path::new(home_directory).is_dir() {
+        return Ok(());
+    }
+    let mounts =
+        fs::read_to_string("/proc/self/mounts").map_err(|_| Error::UserSpaceDeletionFailed {
+            reason: "Unable to read mount table",
+        })?;
+    let mount =
+        containing_mount(&mounts, home_directory).ok_or(Error::UserSpaceDeletionFailed {
+            reason: "No filesystem holds the home directory",
+        })?;
+
+    // Clear limits
+    invoke_set_quota(runner, username, None, &mount.target, |reason| {
+        Error::UserSpaceDeletionFailed { reason }
+    })?;
+
+    // Return home directory to the user
+    release_home_directory(runner, username, home_directory)?;
+
+    // Log
+    println!(
+        "Quota cleared: {user} ({target})",
+        user = username,
+        target = mount.target
+    );
+
+    Ok(())
+}
+
+/// Hands a home directory to root, as sshd requires for a chroot directory.
+pub fn jail_home_directory(runner: &dyn Runner, home_directory: &str) -> Result<(), Error> {
+    invoke_change_owner(runner, "root:root", home_directory, |reason| {
+        Error::UserSpaceCreationFailed { reason }
+    })?;
+    invoke_change_mode(runner, "0755", home_directory)
+}
+
+/// Returns a home directory to its user, so `userdel --remove` deletes it.
+pub fn release_home_directory(
+    runner: &dyn Runner,
+    username: &str,
+    home_directory: &str,
+) -> Result<(), Error> {
+    let owner = format!("{user}:{user}", user = username);
+    invoke_change_owner(runner, &owner, home_directory, |reason| {
+        Error::UserSpaceDeletionFailed { reason }
+    })
+}
+
+fn invoke_change_owner(
+    runner: &dyn Runner,
+    owner: &str,
+    path: &str,
+    failed: fn(&'static str) -> Error,
+) -> Result<(), Error> {
+    let mut cmd = Command::new("chown");
+    cmd.arg(owner);
+    cmd.arg(path);
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| failed("Unable to get exit status"))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(failed("Unable to change owner of home directory").with_output(&cmd, &output))
+    }
+}
+
+fn invoke_change_mode(runner: &dyn Runner, mode: &str, path: &str) -> Result<(), Error> {
+    let mut cmd = Command::new("chmod");
+    cmd.arg(mode);
+    cmd.arg(path);
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| Error::UserSpaceCreationFailed {
+            reason: "Unable to get exit status",
+        })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::UserSpaceCreationFailed {
+            reason: "Unable to change mode of home directory",
+        }
+        .with_output(&cmd, &output))
+    }
+}
+
+/// Sets the hard block and inode limits of `username`, or clears them if
+/// `quota_mb` is `None`. Failures are reported with `failed`.
+fn invoke_set_quota(
+    runner: &dyn Runner,
+    username: &str,
+    quota_mb: Option<u64>,
+    filesystem: &str,
+    failed: fn(&'static str) -> Error,
+) -> Result<(), Error> {
+    let quota_mb = quota_mb.unwrap_or(0);
+    let block_limit = quota_mb
+        .checked_mul(1024) // in 1 KiB blocks
+        .ok_or_else(|| failed("Quota too large"))?;
+    let inode_limit = block_limit / (BYTES_PER_INODE / 1024);
+    let mut cmd = Command::new("setquota");
+    cmd.args(["--user", username]);
+    cmd.args(["0", &block_limit.to_string()]); // no soft limit, hard limit
+    cmd.args(["0", &inode_limit.to_string()]);
+    cmd.arg(filesystem);
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| failed("Unable to get exit status"))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(failed("setquota error").with_output(&cmd, &output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{create_user, RecordingRunner};
+
+    const MOUNTS: &str = "/dev/sda1 / ext4 rw,relatime 0 0\n\
+                          /dev/sdb1 /home ext4 rw,relatime,usrquota 0 0\n";
+
+    fn user() -> User {
+        User {
+            username: "bob".to_string(),
+            base_directory: "/home".to_string(),
+            home_directory: "/home/bob".to_string(),
+        }
+    }
+
+    #[test]
+    fn create_quota_space_limits_blocks_and_inodes() {
+        let runner = RecordingRunner::new();
+        let mut tx = Transaction::new();
+
+        let userspace = create_quota_space(&runner, &user(), 1024, MOUNTS, &mut tx).unwrap();
+
+        assert_eq!(userspace.mount_point, "/home/bob");
+        assert_eq!(userspace.backend, Backend::Quota);
+        assert_eq!(userspace.loop_device, None);
+        assert_eq!(
+            runner.actions(),
+            vec![
+                "chown root:root /home/bob",
+                "chmod 0755 /home/bob",
+                "setquota --user bob 0 1048576 0 65536 /home",
+            ]
+        );
+    }
+
+    #[test]
+    fn create_quota_space_rolls_back_limits() {
+        let runner = RecordingRunner::new();
+        let mut tx = Transaction::new();
+        create_quota_space(&runner, &user(), 1024, MOUNTS, &mut tx).unwrap();
+
+        tx.rollback(&runner, Error::UserJailCreationFailed { reason: "test" });
+
+        assert_eq!(runner.actions()[3], "setquota --user bob 0 0 0 0 /home");
+    }
+
+    #[test]
+    fn create_quota_space_releases_home_before_userdel() {
+        let runner = RecordingRunner::new();
+        let mut tx = Transaction::new();
+        let user = create_user(&runner, "bob", "/home", "", &mut tx).unwrap();
+        create_quota_space(&runner, &user, 1024, MOUNTS, &mut tx).unwrap();
+
+        tx.rollback(&runner, Error::UserJailCreationFailed { reason: "test" });
+
+        assert_eq!(
+            runner.actions()[4..],
+            [
+                "setquota --user bob 0 0 0 0 /home",
+                "chown bob:bob /home/bob",
+                "userdel --remove bob",
+            ]
+        );
+    }
+
+    #[test]
+    fn resize_quota_space_sets_new_limits() {
+        let runner = RecordingRunner::new();
+
+        resize_quota_space(&runner, "bob", "/home/bob", 512, MOUNTS).unwrap();
+
+        assert_eq!(
+            runner.actions(),
+            vec!["setquota --user bob 0 524288 0 32768 /home"]
+        );
+    }
+
+    #[test]
+    fn failed_resize_keeps_setquota_output() {
+        let runner = RecordingRunner::new().fail("setquota", 1, "setquota: Cannot set quota\n");
+
+        let err = resize_quota_space(&runner, "bob", "/home/bob", 512, MOUNTS).unwrap_err();
+
+        assert!(matches!(
+            err.failure(),
+            Error::UserSpaceResizingFailed {
+                reason: "setquota error"
+            }
+        ));
+        assert!(matches!(
+            err,
+            Error::CommandFailed { ref stderr, .. } if stderr == "setquota: Cannot set quota"
+        ));
+        assert_eq!(err.exit_code(), 31);
+    }
+
+    #[test]
+    fn quota_space_requires_filesystem() {
+        let runner = RecordingRunner::new();
+        let mut tx = Transaction::new();
+
+        let result = create_quota_space(&runner, &user(), 1024, "", &mut tx);
+
+        assert!(matches!(
+            result,
+            Err(Error::UserSpaceCreationFailed {
+                reason: "No filesystem holds the home directory"
+            })
+        ));
+        assert!(runner.actions().is_empty());
+    }
+}
@@ -0,0 +1,369 @@
+use crate::{
+    grow_lvm_space, grow_user_space, resize_btrfs_space, resize_quota_space, resize_xfs_space,
+    shrink_lvm_space, shrink_user_space, space_usage, validate_layout, validate_username,
+    AccountRecord, Backend, Error, Filesystem, Inventory, Layout, Runner, Transaction,
+};
+use std::fs;
+
+/// Everything needed to change the quota of a provisioned account.
+#[derive(Debug, Clone)]
+pub struct ResizeRequest {
+    pub username: String,
+    pub quota_mb: u64,
+    pub layout: Layout,
+}
+
+/// Changes the quota of an account recorded in the inventory and stores the
+/// new size.
+///
+/// Shrinking is refused if more space is in use than the new quota allows.
+/// Images grow while they stay mounted; to shrink, they are unmounted and
+/// restored from a copy of the original image if any step fails, which
+/// requires ext4. Logical volumes are resized the same way, without a copy.
+/// Accounts of the other backends only get new limits.
+pub fn resize(runner: &dyn Runner, request: &ResizeRequest) -> Result<AccountRecord, Error> {
+    validate_username(&request.username)?;
+    validate_layout(&request.layout)?;
+    let mut inventory = Inventory::load(&request.layout.inventory_path)?;
+    let mounts =
+        fs::read_to_string("/proc/self/mounts").map_err(|_| Error::UserSpaceResizingFailed {
+            reason: "Unable to read mount table",
+        })?;
+    let passwd = fs::read_to_string("/etc/passwd").map_err(|_| Error::UserSpaceResizingFailed {
+        reason: "Unable to read passwd database",
+    })?;
+    let record =
+        inventory
+            .accounts
+            .get_mut(&request.username)
+            .ok_or(Error::UserSpaceResizingFailed {
+                reason: "Account not found in inventory",
+            })?;
+
+    let (_, usage) = space_usage(
+        record.backend,
+        &record.username,
+        &record.mount_point,
+        &mounts,
+        &passwd,
+    );
+    let used_mb = usage.map(|usage| usage.used_mb);
+
+    resize_account(runner, record, request.quota_mb, &passwd, &mounts, used_mb)?;
+
+    let record = record.clone();
+    inventory.save(runner, &request.layout.inventory_path)?;
+    Ok(record)
+}
+
+fn resize_account(
+    runner: &dyn Runner,
+    record: &mut AccountRecord,
+    quota_mb: u64,
+    passwd: &str,
+    mounts: &str,
+    used_mb: Option<u64>,
+) -> Result<(), Error> {
+    if quota_mb == record.size_mb {
+        println!("Space unchanged: {size}M", size = quota_mb);
+        return Ok(());
+    }
+    match record.backend {
+        Backend::Image if quota_mb < record.size_mb => {
+            check_shrinkable(&record.filesystem)?;
+            let mut tx = Transaction::new();
+            shrink_user_space(
+                runner,
+                &record.image_path,
+                record.allocation,
+                &record.mount_point,
+                quota_mb,
+                used_mb,
+                mounts,
+                &mut tx,
+            )
+            .map_err(|err| tx.rollback(runner, err))?;
+        }
+        Backend::Image => grow_user_space(
+            runner,
+            &record.image_path,
+            record.allocation,
+            parse_filesystem(&record.filesystem)?,
+            &record.mount_point,
+            quota_mb,
+            mounts,
+        )?,
+        Backend::Quota => {
+            check_used_space(record.size_mb, quota_mb, used_mb)?;
+            resize_quota_space(
+                runner,
+                &record.username,
+                &record.home_directory,
+                quota_mb,
+                mounts,
+            )?
+        }
+        Backend::Xfs => {
+            check_used_space(record.size_mb, quota_mb, used_mb)?;
+            resize_xfs_space(
+                runner,
+                &record.username,
+                &record.home_directory,
+                quota_mb,
+                passwd,
+                mounts,
+            )?
+        }
+        Backend::Btrfs => {
+            check_used_space(record.size_mb, quota_mb, used_mb)?;
+            resize_btrfs_space(runner, &record.mount_point, quota_mb)?
+        }
+        Backend::Lvm if quota_mb < record.size_mb => {
+            check_shrinkable(&record.filesystem)?;
+            let mut tx = Transaction::new();
+            shrink_lvm_space(
+                runner,
+                &record.image_path,
+                &record.mount_point,
+                quota_mb,
+                used_mb,
+                mounts,
+                &mut tx,
+            )
+            .map_err(|err| tx.rollback(runner, err))?;
+        }
+        Backend::Lvm => grow_lvm_space(
+            runner,
+            &record.image_path,
+            parse_filesystem(&record.filesystem)?,
+            &record.mount_point,
+            quota_mb,
+            mounts,
+        )?,
+    }
+    record.size_mb = quota_mb;
+    Ok(())
+}
+
+fn parse_filesystem(filesystem: &str) -> Result<Filesystem, Error> {
+    filesystem
+        .parse()
+        .map_err(|_| Error::UserSpaceResizingFailed {
+            reason: "Unsupported filesystem",
+        })
+}
+
+/// Only ext4 spaces can be shrunk; XFS cannot shrink at all.
+fn check_shrinkable(filesystem: &str) -> Result<(), Error> {
+    match parse_filesystem(filesystem)? {
+        Filesystem::Ext4 => Ok(()),
+        _ => Err(Error::UserSpaceResizingFailed {
+            reason: "Shrinking requires ext4",
+        }),
+    }
+}
+
+/// Refuses to shrink a limit below the space already in use.
+fn check_used_space(size_mb: u64, quota_mb: u64, used_mb: Option<u64>) -> Result<(), Error> {
+    if quota_mb >= size_mb {
+        return Ok(());
+    }
+    match used_mb {
+        Some(used_mb) if used_mb < quota_mb => Ok(()),
+        Some(_) => Err(Error::UserSpaceResizingFailed {
+            reason: "Used space exceeds the new quota",
+        }),
+        None => Err(Error::UserSpaceResizingFailed {
+            reason: "Unable to determine used space",
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Allocation, RecordingRunner};
+
+    const MOUNTS: &str = "/dev/loop2 /mnt/bob ext4 rw,relatime 0 0\n";
+    const ASSOCIATED: &str = "/dev/loop2: [2049]:12 (/home/bob/volume)\n";
+
+    fn record() -> AccountRecord {
+        AccountRecord {
+            username: "bob".to_string(),
+            home_directory: "/home/bob".to_string(),
+            image_path: "/home/bob/volume".to_string(),
+            size_mb: 1024,
+            allocation: Allocation::Preallocated,
+            backend: Backend::Image,
+            mount_point: "/mnt/bob".to_string(),
+            filesystem: "ext4".to_string(),
+            format: None,
+            sshd_config_path: "/etc/ssh/sshd_config.d/mkwebuser-bob.conf".to_string(),
+            created_at: 0,
+        }
+    }
+
+    #[test]
+    fn growing_resizes_image_device_and_filesystem() {
+        let runner = RecordingRunner::new();
+        let mut record = record();
+
+        resize_account(&runner, &mut record, 2048, "", MOUNTS, Some(100)).unwrap();
+
+        assert_eq!(record.size_mb, 2048);
+        assert_eq!(
+            runner.actions(),
+            vec![
+                "fallocate --length 2147483648 /home/bob/volume",
+                "losetup --set-capacity /dev/loop2",
+                "resize2fs /dev/loop2",
+            ]
+        );
+    }
+
+    #[test]
+    fn growing_requires_mounted_user_space() {
+        let runner = RecordingRunner::new();
+        let mut record = record();
+
+        let result = resize_account(&runner, &mut record, 2048, "", "", None);
+
+        assert!(matches!(
+            result,
+            Err(Error::UserSpaceResizingFailed {
+                reason: "User space is not mounted"
+            })
+        ));
+        assert_eq!(record.size_mb, 1024);
+        assert!(runner.actions().is_empty());
+    }
+
+    #[test]
+    fn unsafe_usernames_are_refused_before_any_command() {
+        let runner = RecordingRunner::new();
+        let request = ResizeRequest {
+            username: "../bob".to_string(),
+            quota_mb: 2048,
+            layout: Layout::default(),
+        };
+
+        let result = resize(&runner, &request);
+
+        assert!(matches!(result, Err(Error::ValidationFailed { .. })));
+        assert!(runner.actions().is_empty());
+    }
+
+    #[test]
+    fn failed_resize2fs_keeps_recorded_size() {
+        let runner = RecordingRunner::new().respond("resize2fs", 1, "");
+        let mut record = record();
+
+        let result = resize_account(&runner, &mut record, 2048, "", MOUNTS, Some(100));
+
+        assert!(matches!(
+            result.as_ref().map_err(Error::failure),
+            Err(Error::UserSpaceResizingFailed {
+                reason: "resize2fs error"
+            })
+        ));
+        assert_eq!(record.size_mb, 1024);
+    }
+
+    #[test]
+    fn quota_accounts_only_get_new_limits() {
+        let runner = RecordingRunner::new();
+        let mounts = "/dev/sdb1 /home ext4 rw,relatime,usrquota 0 0\n";
+        let mut record = AccountRecord {
+            backend: Backend::Quota,
+            image_path: "/home/bob".to_string(),
+            mount_point: "/home/bob".to_string(),
+            ..record()
+        };
+
+        let result = resize_account(&runner, &mut record, 512, "", mounts, Some(600));
+        assert!(matches!(
+            result,
+            Err(Error::UserSpaceResizingFailed {
+                reason: "Used space exceeds the new quota"
+            })
+        ));
+
+        resize_account(&runner, &mut record, 512, "", mounts, Some(100)).unwrap();
+        assert_eq!(record.size_mb, 512);
+        assert_eq!(
+            runner.actions(),
+            vec!["setquota --user bob 0 524288 0 32768 /home"]
+        );
+    }
+
+    #[test]
+    fn shrinking_checks_and_resizes_offline() {
+        let runner = RecordingRunner::new()
+            .respond("losetup --find", 0, "/dev/loop3\n")
+            .respond("losetup --associated", 0, ASSOCIATED);
+        let mut record = record();
+
+        resize_account(&runner, &mut record, 512, "", MOUNTS, Some(100)).unwrap();
+
+        assert_eq!(record.size_mb, 512);
+        assert_eq!(
+            runner.actions(),
+            vec![
+                "umount /mnt/bob",
+                "losetup --associated /home/bob/volume",
+                "losetup --detach /dev/loop2",
+                "cp --sparse=never --preserve=mode,ownership /home/bob/volume /home/bob/volume.orig",
+                "e2fsck -f -p /home/bob/volume",
+                "resize2fs /home/bob/volume 512M",
+                "truncate --size 536870912 /home/bob/volume",
+                "e2fsck -f -n /home/bob/volume",
+                "losetup --find --show /home/bob/volume",
+                "mount --types ext4 /dev/loop3 /mnt/bob",
+                "rm /home/bob/volume.orig",
+            ]
+        );
+    }
+
+    #[test]
+    fn shrinking_below_used_space_is_refused() {
+        let runner = RecordingRunner::new();
+        let mut record = record();
+
+        let result = resize_account(&runner, &mut record, 512, "", MOUNTS, Some(600));
+
+        assert!(matches!(
+            result,
+            Err(Error::ProvisioningRolledBack { ref error, .. })
+                if matches!(**error, Error::UserSpaceResizingFailed {
+                    reason: "Used space exceeds the new quota"
+                })
+        ));
+        assert_eq!(record.size_mb, 1024);
+        assert!(runner.actions().is_empty());
+    }
+
+    #[test]
+    fn failed_shrink_restores_original_image() {
+        let runner = RecordingRunner::new()
+            .respond("losetup --find", 0, "/dev/loop3\n")
+            .respond("losetup --associated", 0, ASSOCIATED)
+            .respond("resize2fs", 1, "");
+        let mut record = record();
+
+        let result = resize_account(&runner, &mut record, 512, "", MOUNTS, Some(100));
+
+        assert!(matches!(
+            result,
+            Err(Error::ProvisioningRolledBack { ref rollback_errors, .. }) if rollback_errors.is_empty()
+        ));
+        assert_eq!(record.size_mb, 1024);
+        assert_eq!(
+            runner.actions()[6..],
+            [
+                "mv --force /home/bob/volume.orig /home/bob/volume",
+                "losetup --find --show /home/bob/volume",
+                "mount --types ext4 /dev/loop3 /mnt/bob",
+            ]
+        );
+    }
+}
@@ -0,0 +1,393 @@
+use crate::Allocation;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, ExitStatus, Output, Stdio};
+
+/// Performs every change to the host: external commands and filesystem writes.
+///
+/// Reading host state is not routed through here.
+pub trait Runner {
+    /// Runs `cmd` with inherited stdio and waits for it to exit.
+    fn status(&self, cmd: &mut Command) -> io::Result<ExitStatus>;
+
+    /// Runs `cmd` capturing its stdout and stderr.
+    fn output(&self, cmd: &mut Command) -> io::Result<Output>;
+
+    /// Runs `cmd` with `input` on its stdin, capturing its stdout and stderr.
+    /// The input may be secret, so it is never printed or recorded.
+    fn output_with_input(&self, cmd: &mut Command, input: &str) -> io::Result<Output>;
+
+    fn write_file(&self, path: &str, contents: String) -> io::Result<()>;
+
+    /// Replaces `path` with `contents` so readers never see a partial file.
+    fn replace_file(&self, path: &str, contents: String) -> io::Result<()>;
+
+    fn remove_file(&self, path: &str) -> io::Result<()>;
+
+    fn create_dir_all(&self, path: &str) -> io::Result<()>;
+
+    fn remove_dir(&self, path: &str) -> io::Result<()>;
+
+    /// Creates a new root-only image file of `size` bytes.
+    fn create_image(&self, path: &str, size: u64, allocation: Allocation) -> io::Result<()>;
+
+    /// Resizes an existing image file to `size` bytes. Preallocated images
+    /// can only grow; shrinking requires `Allocation::Sparse`, which truncates.
+    fn resize_image(&self, path: &str, size: u64, allocation: Allocation) -> io::Result<()>;
+}
+
+/// Executes everything on the local host.
+pub struct SystemRunner;
+
+impl Runner for SystemRunner {
+    fn status(&self, cmd: &mut Command) -> io::Result<ExitStatus> {
+        cmd.status()
+    }
+
+    fn output(&self, cmd: &mut Command) -> io::Result<Output> {
+        cmd.output()
+    }
+
+    fn output_with_input(&self, cmd: &mut Command, input: &str) -> io::Result<Output> {
+        let mut child = cmd
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        // Dropping stdin closes it, so the command sees the end of its input
+        let written = child
+            .stdin
+            .take()
+            .map_or(Ok(()), |mut stdin| stdin.write_all(input.as_bytes()));
+        let output = child.wait_with_output()?;
+        written.map(|_| output)
+    }
+
+    fn write_file(&self, path: &str, contents: String) -> io::Result<()> {
+        fs::write(path, contents)
+    }
+
+    fn replace_file(&self, path: &str, contents: String) -> io::Result<()> {
+        let temporary_path = format!("{}.tmp", path);
+        let mut file = File::create(&temporary_path)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temporary_path, path)
+    }
+
+    fn remove_file(&self, path: &str) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn create_dir_all(&self, path: &str) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn remove_dir(&self, path: &str) -> io::Result<()> {
+        fs::remove_dir(path)
+    }
+
+    /// A partially created file is removed again if allocation fails.
+    fn create_image(&self, path: &str, size: u64, allocation: Allocation) -> io::Result<()> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?;
+        let result = allocate(&file, size, allocation);
+        if result.is_err() {
+            drop(file);
+            let _ = fs::remove_file(path);
+        }
+        result
+    }
+
+    fn resize_image(&self, path: &str, size: u64, allocation: Allocation) -> io::Result<()> {
+        let file = OpenOptions::new().write(true).open(path)?;
+        allocate(&file, size, allocation)
+    }
+}
+
+/// Sets the size of `file` to `size` bytes, reserving its blocks unless sparse.
+fn allocate(file: &File, size: u64, allocation: Allocation) -> io::Result<()> {
+    match allocation {
+        Allocation::Preallocated => {
+            // SAFETY: the descriptor is owned by `file` and stays open for the call
+            let ret = unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, size as libc::off_t) };
+            if ret == 0 {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        }
+        Allocation::Sparse => file.set_len(size),
+    }
+}
+
+/// Prints every action instead of executing it and reports it as successful.
+///
+/// Captured stdout is a `<program output>` placeholder, so later steps that
+/// depend on it can still be printed.
+pub struct DryRunRunner;
+
+impl DryRunRunner {
+    fn print(&self, action: &str) {
+        println!("[DRY-RUN] {}", action);
+    }
+}
+
+impl Runner for DryRunRunner {
+    fn status(&self, cmd: &mut Command) -> io::Result<ExitStatus> {
+        self.print(&render_command(cmd));
+        Ok(ExitStatus::from_raw(0))
+    }
+
+    fn output(&self, cmd: &mut Command) -> io::Result<Output> {
+        self.print(&render_command(cmd));
+        let placeholder = format!("<{} output>", cmd.get_program().to_string_lossy());
+        Ok(Output {
+            status: ExitStatus::from_raw(0),
+            stdout: placeholder.into_bytes(),
+            stderr: Vec::new(),
+        })
+    }
+
+    fn output_with_input(&self, cmd: &mut Command, _input: &str) -> io::Result<Output> {
+        self.print(&render_command_with_input(cmd));
+        Ok(Output {
+            status: ExitStatus::from_raw(0),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        })
+    }
+
+    fn write_file(&self, path: &str, contents: String) -> io::Result<()> {
+        self.print(&format!("write {path}:", path = path));
+        for line in contents.lines() {
+            println!("    | {}", line);
+        }
+        Ok(())
+    }
+
+    fn replace_file(&self, path: &str, contents: String) -> io::Result<()> {
+        self.print(&format!("replace {path}:", path = path));
+        for line in contents.lines() {
+            println!("    | {}", line);
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &str) -> io::Result<()> {
+        self.print(&format!("rm {path}", path = path));
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &str) -> io::Result<()> {
+        self.print(&format!("mkdir --parents {path}", path = path));
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &str) -> io::Result<()> {
+        self.print(&format!("rmdir {path}", path = path));
+        Ok(())
+    }
+
+    fn create_image(&self, path: &str, size: u64, allocation: Allocation) -> io::Result<()> {
+        self.print(&render_allocate(path, size, allocation));
+        Ok(())
+    }
+
+    fn resize_image(&self, path: &str, size: u64, allocation: Allocation) -> io::Result<()> {
+        self.print(&render_allocate(path, size, allocation));
+        Ok(())
+    }
+}
+
+/// Records every action instead of executing it, for tests.
+///
+/// Actions are recorded in the same shell-like notation `DryRunRunner`
+/// prints. Commands succeed with empty output unless a response was
+/// registered with [`RecordingRunner::respond`].
+#[derive(Default)]
+pub struct RecordingRunner {
+    actions: RefCell<Vec<String>>,
+    files: RefCell<BTreeMap<String, String>>,
+    responses: Vec<(String, i32, String, String)>,
+}
+
+impl RecordingRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes every command whose rendered line starts with `prefix` exit
+    /// with `code` and print `stdout`. Earlier registrations win.
+    pub fn respond(mut self, prefix: &str, code: i32, stdout: &str) -> Self {
+        self.responses
+            .push((prefix.to_string(), code, stdout.to_string(), String::new()));
+        self
+    }
+
+    /// Makes every command whose rendered line starts with `prefix` exit
+    /// with `code` and print `stderr` to its error output.
+    pub fn fail(mut self, prefix: &str, code: i32, stderr: &str) -> Self {
+        self.responses
+            .push((prefix.to_string(), code, String::new(), stderr.to_string()));
+        self
+    }
+
+    pub fn actions(&self) -> Vec<String> {
+        self.actions.borrow().clone()
+    }
+
+    /// Contents of a file written through this runner and not removed since.
+    pub fn file(&self, path: &str) -> Option<String> {
+        self.files.borrow().get(path).cloned()
+    }
+
+    fn record(&self, action: String) {
+        self.actions.borrow_mut().push(action);
+    }
+
+    fn run(&self, cmd: &Command) -> Output {
+        let line = render_command(cmd);
+        let (code, stdout, stderr) = self
+            .responses
+            .iter()
+            .find(|(prefix, _, _, _)| line.starts_with(prefix.as_str()))
+            .map(|(_, code, stdout, stderr)| (*code, stdout.clone(), stderr.clone()))
+            .unwrap_or((0, String::new(), String::new()));
+        self.record(line);
+        Output {
+            // Wait statuses carry the exit code in the second byte
+            status: ExitStatus::from_raw(code << 8),
+            stdout: stdout.into_bytes(),
+            stderr: stderr.into_bytes(),
+        }
+    }
+}
+
+impl Runner for RecordingRunner {
+    fn status(&self, cmd: &mut Command) -> io::Result<ExitStatus> {
+        Ok(self.run(cmd).status)
+    }
+
+    fn output(&self, cmd: &mut Command) -> io::Result<Output> {
+        Ok(self.run(cmd))
+    }
+
+    fn output_with_input(&self, cmd: &mut Command, _input: &str) -> io::Result<Output> {
+        let output = self.run(cmd);
+        if let Some(line) = self.actions.borrow_mut().last_mut() {
+            *line = render_command_with_input(cmd);
+        }
+        Ok(output)
+    }
+
+    fn write_file(&self, path: &str, contents: String) -> io::Result<()> {
+        self.record(format!("write {path}", path = path));
+        self.files.borrow_mut().insert(path.to_string(), contents);
+        Ok(())
+    }
+
+    fn replace_file(&self, path: &str, contents: String) -> io::Result<()> {
+        self.record(format!("replace {path}", path = path));
+        self.files.borrow_mut().insert(path.to_string(), contents);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &str) -> io::Result<()> {
+        self.record(format!("rm {path}", path = path));
+        self.files.borrow_mut().remove(path);
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &str) -> io::Result<()> {
+        self.record(format!("mkdir --parents {path}", path = path));
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &str) -> io::Result<()> {
+        self.record(format!("rmdir {path}", path = path));
+        Ok(())
+    }
+
+    fn create_image(&self, path: &str, size: u64, allocation: Allocation) -> io::Result<()> {
+        self.record(render_allocate(path, size, allocation));
+        Ok(())
+    }
+
+    fn resize_image(&self, path: &str, size: u64, allocation: Allocation) -> io::Result<()> {
+        self.record(render_allocate(path, size, allocation));
+        Ok(())
+    }
+}
+
+pub(crate) fn render_command(cmd: &Command) -> String {
+    let mut line = cmd.get_program().to_string_lossy().into_owned();
+    for arg in cmd.get_args() {
+        let arg = arg.to_string_lossy();
+        line.push(' ');
+        if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || c == '\'') {
+            line.push_str(&format!("'{}'", arg.replace('\'', "'\\''")));
+        } else {
+            line.push_str(&arg);
+        }
+    }
+    line
+}
+
+fn render_command_with_input(cmd: &Command) -> String {
+    format!("{} < <hidden input>", render_command(cmd))
+}
+
+fn render_allocate(path: &str, size: u64, allocation: Allocation) -> String {
+    match allocation {
+        Allocation::Preallocated => format!("fallocate --length {} {}", size, path),
+        Allocation::Sparse => format!("truncate --size {} {}", size, path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_commands_with_quoted_arguments() {
+        let mut cmd = Command::new("useradd");
+        cmd.args(["--comment", "mkwebuser bob", "--shell", ""]);
+        assert_eq!(
+            render_command(&cmd),
+            "useradd --comment 'mkwebuser bob' --shell ''"
+        );
+    }
+
+    #[test]
+    fn recording_runner_replays_responses() {
+        let runner = RecordingRunner::new().respond("losetup --find", 0, "/dev/loop3\n");
+        let output = runner
+            .output(Command::new("losetup").args(["--find", "--show", "/img"]))
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"/dev/loop3\n");
+
+        let status = runner.status(Command::new("sync").arg("-f")).unwrap();
+        assert!(status.success());
+        assert_eq!(
+            runner.actions(),
+            vec!["losetup --find --show /img", "sync -f"]
+        );
+    }
+
+    #[test]
+    fn recording_runner_reports_exit_codes() {
+        let runner = RecordingRunner::new().respond("useradd", 9, "");
+        let status = runner.status(&mut Command::new("useradd")).unwrap();
+        assert_eq!(status.code(), Some(9));
+    }
+}
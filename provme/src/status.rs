@@ -0,0 +1,278 @@
+use crate::{
+    btrfs_usage, containing_mount, mount_source, provisioned_users, user_id, Backend, Error,
+    Inventory, Layout,
+};
+use serde::Serialize;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::Path;
+
+/// Kernel quota command reading the limits and usage of one ID.
+const Q_GETQUOTA: u32 = 0x80_0007;
+
+/// Kind of ID a kernel quota applies to.
+#[derive(Debug, Clone, Copy)]
+pub enum QuotaType {
+    User = 0,
+    Project = 2,
+}
+
+/// Kernel `struct if_dqblk`, as filled in by `Q_GETQUOTA`.
+#[repr(C)]
+#[derive(Default)]
+#[allow(dead_code)] // filled in by the kernel
+struct DiskQuota {
+    block_hard_limit: u64,
+    block_soft_limit: u64,
+    current_space: u64,
+    inode_hard_limit: u64,
+    inode_soft_limit: u64,
+    current_inodes: u64,
+    block_time: u64,
+    inode_time: u64,
+    valid: u32,
+}
+
+/// Space usage of a mounted user space, in MiB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Usage {
+    pub total_mb: u64,
+    pub used_mb: u64,
+    pub free_mb: u64,
+}
+
+/// Live state of a provisioned account.
+#[derive(Debug, Clone, Serialize)]
+pub struct AccountStatus {
+    pub username: String,
+    /// Whether the account is recorded in the inventory. Untracked accounts
+    /// were only found by their mkwebuser comment in the passwd database.
+    pub tracked: bool,
+    pub quota_mb: Option<u64>,
+    pub mount_point: String,
+    pub mounted: bool,
+    pub jailed: bool,
+    /// Only available while the user space is mounted.
+    pub usage: Option<Usage>,
+}
+
+/// Lists every account in the inventory, plus users carrying the mkwebuser
+/// comment in `/etc/passwd`, sorted by username.
+///
+/// Untracked accounts are assumed to use the paths of `layout`.
+pub fn list_accounts(layout: &Layout) -> Result<Vec<AccountStatus>, Error> {
+    let inventory = Inventory::load(&layout.inventory_path)?;
+    let passwd = fs::read_to_string("/etc/passwd").map_err(|_| Error::StatusFailed {
+        reason: "Unable to read passwd database",
+    })?;
+    let mounts = fs::read_to_string("/proc/self/mounts").map_err(|_| Error::StatusFailed {
+        reason: "Unable to read mount table",
+    })?;
+
+    let mut accounts: Vec<AccountStatus> = inventory
+        .accounts
+        .values()
+        .map(|record| {
+            let (mounted, usage) = space_usage(
+                record.backend,
+                &record.username,
+                &record.mount_point,
+                &mounts,
+                &passwd,
+            );
+            account_status(
+                &record.username,
+                true,
+                Some(record.size_mb),
+                &record.mount_point,
+                &record.sshd_config_path,
+                mounted,
+                usage,
+            )
+        })
+        .collect();
+    for username in provisioned_users(&passwd) {
+        if inventory.accounts.contains_key(&username) {
+            continue;
+        }
+        let mount_point = format!("{}/{}", layout.mount_base, username);
+        let config_path = format!("{}/mkwebuser-{}.conf", layout.sshd_config_dir, username);
+        let (mounted, usage) =
+            space_usage(Backend::Image, &username, &mount_point, &mounts, &passwd);
+        accounts.push(account_status(
+            &username,
+            false,
+            None,
+            &mount_point,
+            &config_path,
+            mounted,
+            usage,
+        ));
+    }
+    accounts.sort_by(|a, b| a.username.cmp(&b.username));
+
+    Ok(accounts)
+}
+
+fn account_status(
+    username: &str,
+    tracked: bool,
+    quota_mb: Option<u64>,
+    mount_point: &str,
+    config_path: &str,
+    mounted: bool,
+    usage: Option<Usage>,
+) -> AccountStatus {
+    AccountStatus {
+        username: username.to_string(),
+        tracked,
+        quota_mb,
+        mount_point: mount_point.to_string(),
+        mounted,
+        jailed: is_jailed(username, config_path),
+        usage,
+    }
+}
+
+/// Whether the space of an account is available, and its usage if so.
+///
+/// Image and LVM spaces are available while mounted at `mount_point`; quota and XFS
+/// spaces while the filesystem holding `mount_point` is mounted. XFS project
+/// IDs are the user IDs. Btrfs spaces are available while their subvolume
+/// exists.
+pub fn space_usage(
+    backend: Backend,
+    username: &str,
+    mount_point: &str,
+    mounts: &str,
+    passwd: &str,
+) -> (bool, Option<Usage>) {
+    match backend {
+        Backend::Image | Backend::Lvm => {
+            let mounted = mount_source(mounts, mount_point).is_some();
+            let usage = if mounted {
+                usage(mount_point).ok()
+            } else {
+                None
+            };
+            (mounted, usage)
+        }
+        Backend::Quota | Backend::Xfs => match containing_mount(mounts, mount_point) {
+            Some(mount) => {
+                let quota_type = match backend {
+                    Backend::Xfs => QuotaType::Project,
+                    _ => QuotaType::User,
+                };
+                let usage = user_id(passwd, username)
+                    .and_then(|uid| quota_usage(&mount.source, quota_type, uid).ok());
+                (true, usage)
+            }
+            None => (false, None),
+        },
+        Backend::Btrfs => match btrfs_usage(mount_point) {
+            Ok(usage) => (true, Some(usage)),
+            Err(_) => (Path::new(mount_point).is_dir(), None),
+        },
+    }
+}
+
+/// Whether the sshd configuration at `config_path` jails `username`.
+fn is_jailed(username: &str, config_path: &str) -> bool {
+    let match_line = format!("Match User {}", username);
+    fs::read_to_string(config_path)
+        .map(|config| config.lines().any(|line| line.trim() == match_line))
+        .unwrap_or(false)
+}
+
+/// Queries the usage of the filesystem mounted at `path` with `statvfs(3)`.
+pub fn usage(path: &str) -> io::Result<Usage> {
+    let path =
+        CString::new(path).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    // SAFETY: `statvfs` only writes into the zeroed struct we own
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    let ret = unsafe { libc::statvfs(path.as_ptr(), &mut stat) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let block_size = stat.f_frsize as u64;
+    let to_mb = |blocks: u64| blocks * block_size / (1024 * 1024);
+    Ok(Usage {
+        total_mb: to_mb(stat.f_blocks as u64),
+        used_mb: to_mb((stat.f_blocks - stat.f_bfree) as u64),
+        free_mb: to_mb(stat.f_bavail as u64),
+    })
+}
+
+/// Queries the hard block limit and usage of `id` on the filesystem of
+/// `device` with `quotactl(2)`.
+pub fn quota_usage(device: &str, quota_type: QuotaType, id: u32) -> io::Result<Usage> {
+    let device =
+        CString::new(device).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let command = (Q_GETQUOTA << 8) | quota_type as u32;
+    let mut quota = DiskQuota::default();
+    // SAFETY: `Q_GETQUOTA` only writes a `struct if_dqblk` into `quota`
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_quotactl,
+            command as libc::c_int,
+            device.as_ptr(),
+            id as libc::c_int,
+            &mut quota as *mut DiskQuota,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let total_mb = quota.block_hard_limit / 1024; // in 1 KiB blocks
+    let used_mb = quota.current_space / (1024 * 1024);
+    Ok(Usage {
+        total_mb,
+        used_mb,
+        free_mb: total_mb.saturating_sub(used_mb),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usage_reports_root_filesystem() {
+        let usage = usage("/").unwrap();
+        assert!(usage.total_mb >= usage.used_mb);
+        assert!(usage.total_mb >= usage.free_mb);
+    }
+
+    #[test]
+    fn unmounted_accounts_have_no_usage() {
+        let mounts = "/dev/loop0 /mnt/alice ext4 rw,relatime 0 0\n";
+
+        let (mounted, usage) = space_usage(Backend::Image, "bob", "/mnt/bob", mounts, "");
+        let status = account_status(
+            "bob",
+            true,
+            Some(1024),
+            "/mnt/bob",
+            "/nonexistent/mkwebuser-bob.conf",
+            mounted,
+            usage,
+        );
+
+        assert!(!status.mounted);
+        assert!(!status.jailed);
+        assert_eq!(status.usage, None);
+    }
+
+    #[test]
+    fn quota_accounts_are_available_with_their_filesystem() {
+        let mounts = "/dev/sdb1 /home ext4 rw,relatime,usrquota 0 0\n";
+
+        assert!(space_usage(Backend::Quota, "bob", "/home/bob", mounts, "").0);
+        assert_eq!(
+            space_usage(Backend::Quota, "bob", "/srv/bob", mounts, ""),
+            (false, None)
+        );
+    }
+}
@@ -0,0 +1,109 @@
+use crate::{Error, Runner};
+
+type UndoAction = Box<dyn FnOnce(&dyn Runner) -> Result<(), Error>>;
+
+/// Undo actions of the provisioning steps that succeeded so far.
+#[derive(Default)]
+pub struct Transaction {
+    undo_actions: Vec<(String, UndoAction)>,
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Transaction {
+            undo_actions: Vec::new(),
+        }
+    }
+
+    pub fn on_rollback<F>(&mut self, description: String, action: F)
+    where
+        F: FnOnce(&dyn Runner) -> Result<(), Error> + 'static,
+    {
+        self.undo_actions.push((description, Box::new(action)));
+    }
+
+    /// Runs all undo actions in reverse order and wraps the original error
+    /// together with every undo action that failed.
+    pub fn rollback(self, runner: &dyn Runner, error: Error) -> Error {
+        println!("Rolling back: {error}", error = error);
+        let mut rollback_errors = Vec::new();
+        for (description, action) in self.undo_actions.into_iter().rev() {
+            match action(runner) {
+                Ok(()) => println!("Rolled back: {}", description),
+                Err(err) => {
+                    println!("Rollback failed: {}", description);
+                    rollback_errors.push(err);
+                }
+            }
+        }
+        Error::ProvisioningRolledBack {
+            error: Box::new(error),
+            rollback_errors,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RecordingRunner;
+    use std::process::Command;
+
+    #[test]
+    fn rollback_runs_undo_actions_in_reverse_order() {
+        let runner = RecordingRunner::new();
+        let mut tx = Transaction::new();
+        for step in &["first", "second", "third"] {
+            tx.on_rollback(step.to_string(), move |runner| {
+                runner.status(Command::new("undo").arg(step)).unwrap();
+                Ok(())
+            });
+        }
+
+        let err = tx.rollback(&runner, Error::UserCreationFailed { reason: "test" });
+
+        assert_eq!(
+            runner.actions(),
+            vec!["undo third", "undo second", "undo first"]
+        );
+        match err {
+            Error::ProvisioningRolledBack {
+                error,
+                rollback_errors,
+            } => {
+                assert!(matches!(
+                    *error,
+                    Error::UserCreationFailed { reason: "test" }
+                ));
+                assert!(rollback_errors.is_empty());
+            }
+            err => panic!("unexpected error: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn rollback_continues_after_failed_undo_actions() {
+        let runner = RecordingRunner::new();
+        let mut tx = Transaction::new();
+        tx.on_rollback("first".to_string(), |runner| {
+            runner.status(&mut Command::new("first")).unwrap();
+            Ok(())
+        });
+        tx.on_rollback("second".to_string(), |_| {
+            Err(Error::UserJailDeletionFailed { reason: "test" })
+        });
+
+        let err = tx.rollback(&runner, Error::UserCreationFailed { reason: "test" });
+
+        assert_eq!(runner.actions(), vec!["first"]);
+        match err {
+            Error::ProvisioningRolledBack {
+                rollback_errors, ..
+            } => assert!(matches!(
+                rollback_errors.as_slice(),
+                [Error::UserJailDeletionFailed { reason: "test" }]
+            )),
+            err => panic!("unexpected error: {:?}", err),
+        }
+    }
+}
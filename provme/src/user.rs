@@ -0,0 +1,352 @@
+use crate::{Error, Runner, Transaction};
+use std::process::Command;
+
+#[derive(Debug)]
+pub struct User {
+    pub username: String,
+    pub base_directory: String,
+    pub home_directory: String,
+}
+
+pub fn create_user(
+    runner: &dyn Runner,
+    username: &str,
+    base_directory: &str,
+    passwd: &str,
+    tx: &mut Transaction,
+) -> Result<User, Error> {
+    // Prepare arguments
+    let home_directory = format!("{}/{}", base_directory, username);
+
+    // Reuse a user left behind by an earlier run
+    if let Some((comment, existing_home_directory)) = passwd_entry(passwd, username) {
+        if comment != user_comment(username) || existing_home_directory != home_directory {
+            return Err(Error::UserCreationFailed {
+                reason: "Username already in use",
+            });
+        }
+
+        // Log
+        println!(
+            "User exists: {user} ({home_dir})",
+            user = username,
+            home_dir = home_directory
+        );
+
+        // Instantiate data structure
+        return Ok(User {
+            username: username.to_string(),
+            home_directory,
+            base_directory: base_directory.to_string(),
+        });
+    }
+
+    // Create user
+    invoke_create_user(runner, username, base_directory)?;
+    let undo_username = username.to_string();
+    tx.on_rollback(format!("delete user {}", username), move |runner| {
+        invoke_delete_user(runner, &undo_username)
+    });
+
+    // Log
+    println!(
+        "User created: {user} ({base_dir}/{user})",
+        user = username,
+        base_dir = base_directory
+    );
+
+    // Instantiate data structure
+    Ok(User {
+        username: username.to_string(),
+        home_directory,
+        base_directory: base_directory.to_string(),
+    })
+}
+
+pub fn delete_user(runner: &dyn Runner, username: &str) -> Result<(), Error> {
+    // Delete user together with its home directory
+    invoke_delete_user(runner, username)?;
+
+    // Log
+    println!("User deleted: {user}", user = username);
+
+    Ok(())
+}
+
+/// The passwd comment (GECOS field) marking users created by mkwebuser.
+fn user_comment(username: &str) -> String {
+    format!("mkwebuser {user}", user = username)
+}
+
+/// Lists the users in a passwd database that carry the mkwebuser comment.
+pub fn provisioned_users(passwd: &str) -> Vec<String> {
+    passwd
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(':').collect();
+            match fields.as_slice() {
+                [name, _, _, _, comment, ..] if *comment == user_comment(name) => {
+                    Some(name.to_string())
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Tells whether `username` exists in a passwd database without the
+/// mkwebuser comment, i.e. belongs to someone else.
+pub fn foreign_user(passwd: &str, username: &str) -> bool {
+    passwd_entry(passwd, username).is_some_and(|(comment, _)| comment != user_comment(username))
+}
+
+/// Finds the comment and home directory of `username` in a passwd database.
+fn passwd_entry<'a>(passwd: &'a str, username: &str) -> Option<(&'a str, &'a str)> {
+    passwd.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        match fields.as_slice() {
+            [name, _, _, _, comment, home, ..] if *name == username => Some((*comment, *home)),
+            _ => None,
+        }
+    })
+}
+
+/// Finds the numeric user ID of `username` in a passwd database.
+pub fn user_id(passwd: &str, username: &str) -> Option<u32> {
+    passwd.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        match fields.as_slice() {
+            [name, _, uid, ..] if *name == username => uid.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+fn invoke_create_user(
+    runner: &dyn Runner,
+    username: &str,
+    home_directory: &str,
+) -> Result<(), Error> {
+    let mut cmd = Command::new("useradd");
+    cmd.args(["--base-dir", home_directory]);
+    cmd.args(["--comment", &user_comment(username)]);
+    cmd.args(["--inactive", "-1"]); // never mark user as inactive
+    cmd.args(["--shell", "/usr/sbin/nologin"]); // no interactive shell
+    cmd.arg("--create-home"); // create home directory
+    cmd.arg(username);
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| Error::UserCreationFailed {
+            reason: "Unable to get exit status",
+        })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        // https://linux.die.net/man/8/useradd
+        Err(match output.status.code() {
+            Some(1) => Error::UserCreationFailed {
+                reason: "Unable to update password file",
+            },
+            Some(2) => Error::UserCreationFailed {
+                reason: "Invalid command syntax",
+            },
+            Some(3) => Error::UserCreationFailed {
+                reason: "Invalid argument to option",
+            },
+            Some(4) => Error::UserCreationFailed {
+                reason: "UID already in use",
+            },
+            Some(6) => Error::UserCreationFailed {
+                reason: "The specified group does not exist",
+            },
+            Some(9) => Error::UserCreationFailed {
+                reason: "Username already in use",
+            },
+            Some(10) => Error::UserCreationFailed {
+                reason: "Failed to update group file",
+            },
+            Some(12) => Error::UserCreationFailed {
+                reason: "Failed to create home directory",
+            },
+            Some(13) => Error::UserCreationFailed {
+                reason: "Failed to create mail spool",
+            },
+            Some(14) => Error::UserCreationFailed {
+                reason: "Failed to update SELinux user mapping",
+            },
+            None => Error::UserCreationFailed {
+                reason: "Process terminated by signal",
+            },
+            _ => Error::UserCreationFailed { reason: "Unknown" },
+        }
+        .with_output(&cmd, &output))
+    }
+}
+
+fn invoke_delete_user(runner: &dyn Runner, username: &str) -> Result<(), Error> {
+    let mut cmd = Command::new("userdel");
+    cmd.arg("--remove"); // remove home directory and mail spool
+    cmd.arg(username);
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| Error::UserDeletionFailed {
+            reason: "Unable to get exit status",
+        })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        // https://linux.die.net/man/8/userdel
+        Err(match output.status.code() {
+            Some(1) => Error::UserDeletionFailed {
+                reason: "Unable to update password file",
+            },
+            Some(2) => Error::UserDeletionFailed {
+                reason: "Invalid command syntax",
+            },
+            Some(6) => Error::UserDeletionFailed {
+                reason: "The specified user does not exist",
+            },
+            Some(8) => Error::UserDeletionFailed {
+                reason: "User currently logged in",
+            },
+            Some(10) => Error::UserDeletionFailed {
+                reason: "Failed to update group file",
+            },
+            Some(12) => Error::UserDeletionFailed {
+                reason: "Failed to remove home directory",
+            },
+            None => Error::UserDeletionFailed {
+                reason: "Process terminated by signal",
+            },
+            _ => Error::UserDeletionFailed { reason: "Unknown" },
+        }
+        .with_output(&cmd, &output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RecordingRunner;
+
+    #[test]
+    fn create_user_runs_useradd() {
+        let runner = RecordingRunner::new();
+        let mut tx = Transaction::new();
+
+        let user = create_user(&runner, "bob", "/srv/home", "", &mut tx).unwrap();
+
+        assert_eq!(user.home_directory, "/srv/home/bob");
+        assert_eq!(
+            runner.actions(),
+            vec![
+                "useradd --base-dir /srv/home --comment 'mkwebuser bob' --inactive -1 \
+                 --shell /usr/sbin/nologin --create-home bob"
+            ]
+        );
+    }
+
+    #[test]
+    fn create_user_rolls_back_with_userdel() {
+        let runner = RecordingRunner::new();
+        let mut tx = Transaction::new();
+        create_user(&runner, "bob", "/home", "", &mut tx).unwrap();
+
+        tx.rollback(&runner, Error::UserCreationFailed { reason: "test" });
+
+        assert_eq!(runner.actions()[1], "userdel --remove bob");
+    }
+
+    #[test]
+    fn create_user_maps_useradd_exit_codes() {
+        let cases = [
+            (1, "Unable to update password file"),
+            (4, "UID already in use"),
+            (9, "Username already in use"),
+            (12, "Failed to create home directory"),
+            (42, "Unknown"),
+        ];
+        for (code, expected) in cases.iter() {
+            let runner = RecordingRunner::new().respond("useradd", *code, "");
+            let mut tx = Transaction::new();
+            match create_user(&runner, "bob", "/home", "", &mut tx)
+                .as_ref()
+                .map_err(Error::failure)
+            {
+                Err(Error::UserCreationFailed { reason }) => assert_eq!(reason, expected),
+                other => panic!("exit code {}: unexpected result {:?}", code, other),
+            }
+        }
+    }
+
+    #[test]
+    fn create_user_reuses_user_from_earlier_run() {
+        let runner = RecordingRunner::new();
+        let mut tx = Transaction::new();
+        let passwd = "bob:x:1001:1001:mkwebuser bob:/home/bob:/usr/sbin/nologin\n";
+
+        let user = create_user(&runner, "bob", "/home", passwd, &mut tx).unwrap();
+        tx.rollback(&runner, Error::UserJailCreationFailed { reason: "test" });
+
+        assert_eq!(user.home_directory, "/home/bob");
+        assert!(runner.actions().is_empty());
+    }
+
+    #[test]
+    fn create_user_refuses_foreign_users() {
+        let cases = [
+            "bob:x:1001:1001:Bob:/home/bob:/bin/bash\n",
+            "bob:x:1001:1001:mkwebuser bob:/srv/home/bob:/usr/sbin/nologin\n",
+        ];
+        for passwd in cases.iter() {
+            let runner = RecordingRunner::new();
+            let mut tx = Transaction::new();
+
+            let result = create_user(&runner, "bob", "/home", passwd, &mut tx);
+
+            assert!(matches!(
+                result,
+                Err(Error::UserCreationFailed {
+                    reason: "Username already in use"
+                })
+            ));
+            assert!(runner.actions().is_empty());
+        }
+    }
+
+    #[test]
+    fn delete_user_maps_userdel_exit_codes() {
+        let cases = [
+            (6, "The specified user does not exist"),
+            (8, "User currently logged in"),
+            (12, "Failed to remove home directory"),
+        ];
+        for (code, expected) in cases.iter() {
+            let runner = RecordingRunner::new().respond("userdel", *code, "");
+            match delete_user(&runner, "bob").as_ref().map_err(Error::failure) {
+                Err(Error::UserDeletionFailed { reason }) => assert_eq!(reason, expected),
+                other => panic!("exit code {}: unexpected result {:?}", code, other),
+            }
+        }
+    }
+
+    #[test]
+    fn provisioned_users_are_found_by_comment() {
+        let passwd = "root:x:0:0:root:/root:/bin/bash\n\
+                      bob:x:1001:1001:mkwebuser bob:/home/bob:/usr/sbin/nologin\n\
+                      eve:x:1002:1002:mkwebuser bob:/home/eve:/usr/sbin/nologin\n\
+                      carol:x:1003:1003:Carol:/home/carol:/bin/bash\n";
+
+        assert_eq!(provisioned_users(passwd), vec!["bob"]);
+    }
+
+    #[test]
+    fn foreign_users_are_told_apart() {
+        let passwd = "bob:x:1001:1001:mkwebuser bob:/home/bob:/usr/sbin/nologin\n\
+                      carol:x:1003:1003:Carol:/home/carol:/bin/bash\n";
+
+        assert!(!foreign_user(passwd, "bob"));
+        assert!(foreign_user(passwd, "carol"));
+        assert!(!foreign_user(passwd, "dave"));
+    }
+}
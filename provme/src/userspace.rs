@@ -0,0 +1,1503 @@
+use crate::{has_superblock, Backend, Error, Filesystem, FormatOptions, Runner, Transaction, User};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const USER_SPACE_NAME: &str = "volume";
+
+/// How the image file backing a user space is allocated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Allocation {
+    /// Reserve every block up front with `fallocate(2)`.
+    #[default]
+    Preallocated,
+    /// Create a sparse file that only consumes blocks once they are written.
+    Sparse,
+}
+
+impl FromStr for Allocation {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "preallocated" => Ok(Allocation::Preallocated),
+            "sparse" => Ok(Allocation::Sparse),
+            _ => Err("expected `preallocated` or `sparse`"),
+        }
+    }
+}
+
+impl fmt::Display for Allocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Allocation::Preallocated => write!(f, "preallocated"),
+            Allocation::Sparse => write!(f, "sparse"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UserSpace {
+    pub name: String,
+    pub path: String,
+    pub size_mb: u64,
+    pub allocation: Allocation,
+    pub filesystem: String,
+    /// Only set for user spaces formatted by provme.
+    pub format: Option<FormatOptions>,
+    pub backend: Backend,
+    /// Only set for user spaces attached through a loop device.
+    pub loop_device: Option<String>,
+    /// Directory the user is jailed to.
+    pub mount_point: String,
+}
+
+/// Creates, formats and mounts the image of `user` at
+/// `<image_store>/<username>.img`. The image store is only accessible by
+/// root.
+#[allow(clippy::too_many_arguments)]
+pub fn create_user_space(
+    runner: &dyn Runner,
+    user: &User,
+    quota_mb: u64,
+    allocation: Allocation,
+    format: &FormatOptions,
+    image_store: &str,
+    mount_base: &str,
+    mounts: &str,
+    tx: &mut Transaction,
+) -> Result<UserSpace, Error> {
+    // Prepare arguments
+    let name = USER_SPACE_NAME;
+    let path = image_path(image_store, &user.username);
+    let mount_point = mount_point(mount_base, &user.username);
+    let size = quota_mb
+        .checked_mul(1024 * 1024)
+        .ok_or(Error::UserSpaceCreationFailed {
+            reason: "Quota too large",
+        })?;
+    let existing_size = fs::metadata(&path).ok().map(|metadata| metadata.len());
+
+    // Refuse to take over a mount point used by another device
+    let mounted_device = mount_source(mounts, &mount_point);
+    if let Some(device) = &mounted_device {
+        let associated = match existing_size {
+            Some(_) => invoke_find_loop_devices(runner, &path)?,
+            None => Vec::new(),
+        };
+        if !associated.contains(device) {
+            return Err(Error::UserSpaceMountingFailed {
+                reason: "Mount point is in use by another device",
+            });
+        }
+    }
+
+    // Create user space, unless an earlier run did
+    match existing_size {
+        Some(existing_size) if existing_size == size => {
+            // Log
+            println!(
+                "Space exists: {size}M ({path})",
+                size = quota_mb,
+                path = path
+            );
+        }
+        Some(_) => {
+            return Err(Error::UserSpaceCreationFailed {
+                reason: "Image exists with a different size",
+            });
+        }
+        None => {
+            create_image_store(runner, image_store)?;
+            invoke_create_user_space(runner, &path, quota_mb, allocation)?;
+            let undo_path = path.clone();
+            tx.on_rollback(format!("delete {}", path), move |runner| {
+                invoke_delete_user_space(runner, &undo_path)
+            });
+
+            // Log
+            println!(
+                "Space created: {size}M {allocation} ({path})",
+                size = quota_mb,
+                allocation = allocation,
+                path = path,
+            );
+        }
+    }
+
+    // Format user space, unless it already holds a filesystem
+    let filesystem = format.filesystem;
+    if has_superblock(&path, filesystem) {
+        // Log
+        println!(
+            "Space formatted earlier: {filesystem} ({path})",
+            filesystem = filesystem,
+            path = path
+        );
+    } else {
+        invoke_format_user_space(runner, &path, format)?;
+
+        // Log
+        println!(
+            "Space formatted: {filesystem} ({path})",
+            filesystem = filesystem,
+            path = path
+        );
+    }
+
+    // Attach and mount user space, unless an earlier run did
+    let loop_device = match mounted_device {
+        Some(loop_device) => {
+            // Log
+            println!(
+                "Space mounted earlier: {device} ({mount_point})",
+                device = loop_device,
+                mount_point = mount_point,
+            );
+            loop_device
+        }
+        None => {
+            // Attach user space to a loop device
+            let attached = match existing_size {
+                Some(_) => invoke_find_loop_devices(runner, &path)?.into_iter().next(),
+                None => None,
+            };
+            let loop_device = match attached {
+                Some(loop_device) => loop_device,
+                None => {
+                    let loop_device = invoke_attach_user_space(runner, &path)?;
+                    let undo_device = loop_device.clone();
+                    tx.on_rollback(format!("detach {}", loop_device), move |runner| {
+                        invoke_detach_user_space(runner, &undo_device)
+                    });
+                    loop_device
+                }
+            };
+
+            // Mount user space
+            create_mount_point(runner, &mount_point, tx)?;
+            invoke_mount_user_space(runner, &loop_device, &mount_point, filesystem)?;
+            let undo_mount_point = mount_point.clone();
+            tx.on_rollback(format!("unmount {}", mount_point), move |runner| {
+                invoke_unmount_user_space(runner, &undo_mount_point)
+            });
+
+            // Log
+            println!(
+                "Space mounted: {device} ({mount_point})",
+                device = loop_device,
+                mount_point = mount_point,
+            );
+            loop_device
+        }
+    };
+
+    // Instantiate data structure
+    Ok(UserSpace {
+        name: name.to_string(),
+        path,
+        size_mb: quota_mb,
+        allocation,
+        filesystem: filesystem.to_string(),
+        format: Some(format.clone()),
+        backend: Backend::Image,
+        loop_device: Some(loop_device),
+        mount_point,
+    })
+}
+
+/// Unmounts and deletes or archives the image of `username`, skipping
+/// whatever is already gone. Images that were never migrated out of the
+/// home directory are found there.
+pub fn delete_user_space(
+    runner: &dyn Runner,
+    username: &str,
+    home_directory: &str,
+    image_store: &str,
+    mount_base: &str,
+    archive_directory: Option<&str>,
+) -> Result<(), Error> {
+    // Prepare arguments
+    let path = image_path(image_store, username);
+    let legacy_path = legacy_image_path(home_directory);
+    let path = if !Path::new(&path).exists() && Path::new(&legacy_path).exists() {
+        legacy_path
+    } else {
+        path
+    };
+    let mount_point = mount_point(mount_base, username);
+
+    // Unmount user space
+    if let Some(device) = find_mount_source(&mount_point)? {
+        invoke_unmount_user_space(runner, &mount_point)?;
+        detach_user_space(runner, &path)?;
+
+        // Log
+        println!(
+            "Space unmounted: {device} ({mount_point})",
+            device = device,
+            mount_point = mount_point,
+        );
+    }
+    remove_mount_point(runner, &mount_point)?;
+
+    // Remove or archive user space
+    if !Path::new(&path).exists() {
+        return Ok(());
+    }
+    detach_user_space(runner, &path)?;
+    match archive_directory {
+        Some(archive_directory) => {
+            let archive_path = archive_path(archive_directory, username);
+            invoke_archive_user_space(runner, &path, &archive_path)?;
+
+            // Log
+            println!(
+                "Space archived: {path} ({archive_path})",
+                path = path,
+                archive_path = archive_path,
+            );
+        }
+        None => {
+            invoke_delete_user_space(runner, &path)?;
+
+            // Log
+            println!("Space deleted: {path}", path = path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Grows the image of a mounted user space to `quota_mb` and resizes its
+/// filesystem online.
+///
+/// If growing the filesystem fails, the image stays enlarged; running the
+/// resize again completes it.
+pub fn grow_user_space(
+    runner: &dyn Runner,
+    path: &str,
+    allocation: Allocation,
+    filesystem: Filesystem,
+    mount_point: &str,
+    quota_mb: u64,
+    mounts: &str,
+) -> Result<(), Error> {
+    // Prepare arguments
+    let device = mount_source(mounts, mount_point).ok_or(Error::UserSpaceResizingFailed {
+        reason: "User space is not mounted",
+    })?;
+    let size = quota_mb
+        .checked_mul(1024 * 1024)
+        .ok_or(Error::UserSpaceResizingFailed {
+            reason: "Quota too large",
+        })?;
+
+    // Grow image and let the loop device pick up the new size
+    runner.resize_image(path, size, allocation).map_err(|err| {
+        Error::UserSpaceResizingFailed {
+            reason: "Unable to grow image",
+        }
+        .with_source(err)
+    })?;
+    invoke_refresh_loop_device(runner, &device)?;
+
+    // Grow filesystem while mounted
+    invoke_grow_filesystem(runner, filesystem, &device, mount_point)?;
+
+    // Log
+    println!(
+        "Space resized: {size}M ({path})",
+        size = quota_mb,
+        path = path,
+    );
+
+    Ok(())
+}
+
+/// Shrinks the image of a mounted ext4 user space to `quota_mb`.
+///
+/// The user space is unmounted, checked, shrunk offline, checked again and
+/// remounted. A copy of the original image is kept until the user space is
+/// mounted again; if any step fails, rolling back `tx` restores the copy
+/// and remounts it.
+#[allow(clippy::too_many_arguments)]
+pub fn shrink_user_space(
+    runner: &dyn Runner,
+    path: &str,
+    allocation: Allocation,
+    mount_point: &str,
+    quota_mb: u64,
+    used_mb: Option<u64>,
+    mounts: &str,
+    tx: &mut Transaction,
+) -> Result<(), Error> {
+    // Prepare arguments
+    let device = mount_source(mounts, mount_point).ok_or(Error::UserSpaceResizingFailed {
+        reason: "User space is not mounted",
+    })?;
+    let size = quota_mb
+        .checked_mul(1024 * 1024)
+        .ok_or(Error::UserSpaceResizingFailed {
+            reason: "Quota too large",
+        })?;
+    let backup_path = backup_path(path);
+
+    // Refuse to cut off data
+    let used_mb = used_mb.ok_or(Error::UserSpaceResizingFailed {
+        reason: "Unable to determine used space",
+    })?;
+    if used_mb >= quota_mb {
+        return Err(Error::UserSpaceResizingFailed {
+            reason: "Used space exceeds the new quota",
+        });
+    }
+
+    // Unmount user space
+    invoke_unmount_user_space(runner, &mount_point)?;
+    detach_user_space(runner, path)?;
+    let undo_path = path.to_string();
+    let undo_mount_point = mount_point.to_string();
+    tx.on_rollback(format!("remount {}", mount_point), move |runner| {
+        let device = invoke_attach_user_space(runner, &undo_path)?;
+        invoke_mount_user_space(runner, &device, &undo_mount_point, Filesystem::Ext4)
+    });
+
+    // Log
+    println!(
+        "Space unmounted: {device} ({mount_point})",
+        device = device,
+        mount_point = mount_point,
+    );
+
+    // Keep a copy of the original image
+    invoke_copy_user_space(runner, &path, &backup_path, allocation)?;
+    let undo_path = path.to_string();
+    let undo_backup_path = backup_path.clone();
+    tx.on_rollback(format!("restore {}", path), move |runner| {
+        invoke_restore_user_space(runner, &undo_backup_path, &undo_path)
+    });
+
+    // Shrink filesystem, then image
+    invoke_check_filesystem(runner, &path, true)?;
+    invoke_resize_filesystem(runner, &path, Some(quota_mb))?;
+    runner
+        .resize_image(path, size, Allocation::Sparse)
+        .map_err(|err| {
+            Error::UserSpaceResizingFailed {
+                reason: "Unable to shrink image",
+            }
+            .with_source(err)
+        })?;
+    invoke_check_filesystem(runner, &path, false)?;
+
+    // Remount user space
+    let loop_device = invoke_attach_user_space(runner, &path)?;
+    let undo_device = loop_device.clone();
+    tx.on_rollback(format!("detach {}", loop_device), move |runner| {
+        invoke_detach_user_space(runner, &undo_device)
+    });
+    invoke_mount_user_space(runner, &loop_device, &mount_point, Filesystem::Ext4)?;
+    let undo_mount_point = mount_point.to_string();
+    tx.on_rollback(format!("unmount {}", mount_point), move |runner| {
+        invoke_unmount_user_space(runner, &undo_mount_point)
+    });
+
+    // Drop the copy of the original image
+    runner
+        .remove_file(&backup_path)
+        .map_err(|_| Error::UserSpaceResizingFailed {
+            reason: "Unable to remove image backup",
+        })?;
+
+    // Log
+    println!(
+        "Space resized: {size}M ({path})",
+        size = quota_mb,
+        path = path,
+    );
+
+    Ok(())
+}
+
+/// Moves the image at `path` to `new_path` in `image_store`, unmounting it
+/// first and mounting it again afterwards if it is mounted at
+/// `mount_point`. If any step fails, rolling back `tx` moves the image back
+/// and remounts it.
+#[allow(clippy::too_many_arguments)]
+pub fn move_user_space(
+    runner: &dyn Runner,
+    path: &str,
+    new_path: &str,
+    image_store: &str,
+    filesystem: Filesystem,
+    mount_point: &str,
+    mounts: &str,
+    tx: &mut Transaction,
+) -> Result<(), Error> {
+    // Prepare arguments
+    if Path::new(new_path).exists() {
+        return Err(Error::UserSpaceMigrationFailed {
+            reason: "Image store already holds an image of the account",
+        });
+    }
+    let device = mount_source(mounts, mount_point);
+
+    // Unmount user space
+    if let Some(device) = &device {
+        invoke_unmount_user_space(runner, &mount_point)?;
+        detach_user_space(runner, path)?;
+        let undo_path = path.to_string();
+        let undo_mount_point = mount_point.to_string();
+        tx.on_rollback(format!("remount {}", mount_point), move |runner| {
+            let device = invoke_attach_user_space(runner, &undo_path)?;
+            invoke_mount_user_space(runner, &device, &undo_mount_point, filesystem)
+        });
+
+        // Log
+        println!(
+            "Space unmounted: {device} ({mount_point})",
+            device = device,
+            mount_point = mount_point,
+        );
+    }
+
+    // Move image into the image store
+    create_image_store(runner, image_store)?;
+    invoke_move_user_space(runner, &path, &new_path)?;
+    let undo_path = path.to_string();
+    let undo_new_path = new_path.to_string();
+    tx.on_rollback(format!("move {} back", new_path), move |runner| {
+        invoke_move_user_space(runner, &undo_new_path, &undo_path)
+    });
+    invoke_change_mode(runner, "0600", new_path)?;
+
+    // Log
+    println!(
+        "Space moved: {path} ({new_path})",
+        path = path,
+        new_path = new_path,
+    );
+
+    // Remount user space
+    if device.is_some() {
+        let loop_device = invoke_attach_user_space(runner, &new_path)?;
+        let undo_device = loop_device.clone();
+        tx.on_rollback(format!("detach {}", loop_device), move |runner| {
+            invoke_detach_user_space(runner, &undo_device)
+        });
+        invoke_mount_user_space(runner, &loop_device, &mount_point, filesystem)?;
+
+        // Log
+        println!(
+            "Space mounted: {device} ({mount_point})",
+            device = loop_device,
+            mount_point = mount_point,
+        );
+    }
+
+    Ok(())
+}
+
+/// Creates the directory at `mount_point` unless it exists. Rolling back
+/// `tx` removes it again.
+pub fn create_mount_point(
+    runner: &dyn Runner,
+    mount_point: &str,
+    tx: &mut Transaction,
+) -> Result<(), Error> {
+    if Path::new(mount_point).is_dir() {
+        return Ok(());
+    }
+    runner
+        .create_dir_all(mount_point)
+        .map_err(|_| Error::UserSpaceMountingFailed {
+            reason: "Unable to create mount point",
+        })?;
+    let undo_mount_point = mount_point.to_string();
+    tx.on_rollback(format!("remove {}", mount_point), move |runner| {
+        runner
+            .remove_dir(&undo_mount_point)
+            .map_err(|_| Error::UserSpaceUnmountingFailed {
+                reason: "Unable to remove mount point",
+            })
+    });
+    Ok(())
+}
+
+/// Removes the empty directory at `mount_point`, if it exists.
+pub fn remove_mount_point(runner: &dyn Runner, mount_point: &str) -> Result<(), Error> {
+    if !Path::new(mount_point).is_dir() {
+        return Ok(());
+    }
+    runner
+        .remove_dir(mount_point)
+        .map_err(|_| Error::UserSpaceUnmountingFailed {
+            reason: "Unable to remove mount point",
+        })
+}
+
+/// Detaches every loop device still backed by the image at `path`. Loop
+/// devices set up by a mount unit are released by `umount` already.
+fn detach_user_space(runner: &dyn Runner, path: &str) -> Result<(), Error> {
+    for device in invoke_find_loop_devices(runner, &path)? {
+        invoke_detach_user_space(runner, &device)?;
+    }
+    Ok(())
+}
+
+/// Path of the image of `username` in `image_store`.
+pub fn image_path(image_store: &str, username: &str) -> String {
+    format!(
+        "{image_store}/{user}.img",
+        image_store = image_store,
+        user = username
+    )
+}
+
+/// Path of the image inside the home directory, where images were placed
+/// before the image store existed.
+fn legacy_image_path(home_directory: &str) -> String {
+    format!(
+        "{home_dir}/{name}",
+        home_dir = home_directory,
+        name = USER_SPACE_NAME
+    )
+}
+
+fn mount_point(mount_base: &str, username: &str) -> String {
+    format!(
+        "{mount_base}/{user}",
+        mount_base = mount_base,
+        user = username
+    )
+}
+
+fn backup_path(path: &str) -> String {
+    format!("{path}.orig", path = path)
+}
+
+fn archive_path(archive_directory: &str, username: &str) -> String {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    format!(
+        "{archive_dir}/{user}-{timestamp}.img",
+        archive_dir = archive_directory,
+        user = username,
+        timestamp = timestamp
+    )
+}
+
+/// Looks up the device mounted at `mount_point` in the kernel mount table.
+fn find_mount_source(mount_point: &str) -> Result<Option<String>, Error> {
+    let mounts =
+        fs::read_to_string("/proc/self/mounts").map_err(|_| Error::UserSpaceUnmountingFailed {
+            reason: "Unable to read mount table",
+        })?;
+    Ok(mount_source(&mounts, mount_point))
+}
+
+/// Finds the source mounted at `mount_point` in a `/proc/self/mounts` listing.
+pub fn mount_source(mounts: &str, mount_point: &str) -> Option<String> {
+    mounts.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next()) {
+            (Some(source), Some(target)) if target == mount_point => Some(source.to_string()),
+            _ => None,
+        }
+    })
+}
+
+/// An entry of the kernel mount table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mount {
+    pub source: String,
+    pub target: String,
+    pub filesystem: String,
+}
+
+/// Finds the mount holding `path` in a `/proc/self/mounts` listing, which is
+/// the one with the longest target containing it.
+pub fn containing_mount(mounts: &str, path: &str) -> Option<Mount> {
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some(source), Some(target), Some(filesystem))
+                    if Path::new(path).starts_with(target) =>
+                {
+                    Some(Mount {
+                        source: source.to_string(),
+                        target: target.to_string(),
+                        filesystem: filesystem.to_string(),
+                    })
+                }
+                _ => None,
+            }
+        })
+        .max_by_key(|mount| mount.target.len())
+}
+
+fn invoke_create_user_space<P>(
+    runner: &dyn Runner,
+    path: &P,
+    quota_mb: u64,
+    allocation: Allocation,
+) -> Result<(), Error>
+where
+    P: AsRef<str>,
+{
+    let path: &str = path.as_ref();
+    let size = quota_mb
+        .checked_mul(1024 * 1024)
+        .ok_or(Error::UserSpaceCreationFailed {
+            reason: "Quota too large",
+        })?;
+    runner.create_image(path, size, allocation).map_err(|err| {
+        Error::UserSpaceCreationFailed {
+            reason: "Unable to allocate image",
+        }
+        .with_source(err)
+    })
+}
+
+/// Creates the image store, accessible by root only.
+fn create_image_store(runner: &dyn Runner, image_store: &str) -> Result<(), Error> {
+    runner
+        .create_dir_all(image_store)
+        .map_err(|_| Error::UserSpaceCreationFailed {
+            reason: "Unable to create image store",
+        })?;
+    invoke_change_mode(runner, "0700", image_store)
+}
+
+fn invoke_change_mode(runner: &dyn Runner, mode: &str, path: &str) -> Result<(), Error> {
+    let mut cmd = Command::new("chmod");
+    cmd.arg(mode);
+    cmd.arg(path);
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| Error::UserSpaceCreationFailed {
+            reason: "Unable to get exit status",
+        })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::UserSpaceCreationFailed {
+            reason: "Unable to restrict permissions",
+        }
+        .with_output(&cmd, &output))
+    }
+}
+
+fn invoke_move_user_space<P, N>(runner: &dyn Runner, path: &P, new_path: &N) -> Result<(), Error>
+where
+    P: AsRef<str>,
+    N: AsRef<str>,
+{
+    let path: &str = path.as_ref();
+    let new_path: &str = new_path.as_ref();
+    let mut cmd = Command::new("mv");
+    cmd.arg("--no-clobber");
+    cmd.arg(path);
+    cmd.arg(new_path);
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| Error::UserSpaceMigrationFailed {
+            reason: "Unable to get exit status",
+        })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::UserSpaceMigrationFailed {
+            reason: "Unable to move image",
+        }
+        .with_output(&cmd, &output))
+    }
+}
+
+fn invoke_delete_user_space<P>(runner: &dyn Runner, path: &P) -> Result<(), Error>
+where
+    P: AsRef<str>,
+{
+    let path: &str = path.as_ref();
+    runner
+        .remove_file(path)
+        .map_err(|_| Error::UserSpaceDeletionFailed {
+            reason: "Unable to remove image",
+        })
+}
+
+pub fn invoke_format_user_space<P>(
+    runner: &dyn Runner,
+    path: &P,
+    format: &FormatOptions,
+) -> Result<(), Error>
+where
+    P: AsRef<str>,
+{
+    let path: &str = path.as_ref();
+    let filesystem = format.filesystem;
+    let mut cmd = Command::new(format!("mkfs.{}", filesystem));
+    if let Some(percent) = format.reserved_percent {
+        cmd.args(["-m", &percent.to_string()]);
+    }
+    if let Some(ratio) = format.inode_ratio {
+        cmd.args(["-i", &ratio.to_string()]);
+    }
+    if let Some(count) = format.inode_count {
+        cmd.args(["-N", &count.to_string()]);
+    }
+    if let Some(label) = &format.label {
+        cmd.args(["-L", label]);
+    }
+    if let Some(features) = &format.features {
+        cmd.args(["-O", features]);
+    }
+    cmd.arg(path);
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| Error::UserSpaceFormattingFailed {
+            reason: "Unable to get exit status",
+        })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::UserSpaceFormattingFailed {
+            reason: match filesystem {
+                Filesystem::Ext4 => "mkfs.ext4 error",
+                Filesystem::Xfs => "mkfs.xfs error",
+                Filesystem::Btrfs => "mkfs.btrfs error",
+            },
+        }
+        .with_output(&cmd, &output))
+    }
+}
+
+fn invoke_attach_user_space<P>(runner: &dyn Runner, path: &P) -> Result<String, Error>
+where
+    P: AsRef<str>,
+{
+    let path: &str = path.as_ref();
+    let mut cmd = Command::new("losetup");
+    cmd.arg("--find"); // use the first unused loop device
+    cmd.arg("--show"); // print the name of the assigned device
+    cmd.arg(path);
+    let output: Output = runner
+        .output(&mut cmd)
+        .map_err(|_| Error::UserSpaceMountingFailed {
+            reason: "Unable to get exit status",
+        })?;
+    if !output.status.success() {
+        return Err(Error::UserSpaceMountingFailed {
+            reason: "losetup error",
+        }
+        .with_output(&cmd, &output));
+    }
+    let device = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if device.is_empty() {
+        Err(Error::UserSpaceMountingFailed {
+            reason: "losetup did not report a loop device",
+        })
+    } else {
+        Ok(device)
+    }
+}
+
+pub fn invoke_mount_user_space<D, P>(
+    runner: &dyn Runner,
+    device: &D,
+    mount_point: &P,
+    filesystem: Filesystem,
+) -> Result<(), Error>
+where
+    D: AsRef<str>,
+    P: AsRef<str>,
+{
+    let device: &str = device.as_ref();
+    let mount_point: &str = mount_point.as_ref();
+    let mut cmd = Command::new("mount");
+    cmd.args(["--types", &filesystem.to_string()]);
+    cmd.arg(device);
+    cmd.arg(mount_point);
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| Error::UserSpaceMountingFailed {
+            reason: "Unable to get exit status",
+        })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::UserSpaceMountingFailed {
+            reason: "mount error",
+        }
+        .with_output(&cmd, &output))
+    }
+}
+
+fn invoke_refresh_loop_device<D>(runner: &dyn Runner, device: &D) -> Result<(), Error>
+where
+    D: AsRef<str>,
+{
+    let device: &str = device.as_ref();
+    let mut cmd = Command::new("losetup");
+    cmd.arg("--set-capacity"); // reread the size of the backing file
+    cmd.arg(device);
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| Error::UserSpaceResizingFailed {
+            reason: "Unable to get exit status",
+        })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::UserSpaceResizingFailed {
+            reason: "losetup error",
+        }
+        .with_output(&cmd, &output))
+    }
+}
+
+pub fn invoke_resize_filesystem<D>(
+    runner: &dyn Runner,
+    device: &D,
+    size_mb: Option<u64>,
+) -> Result<(), Error>
+where
+    D: AsRef<str>,
+{
+    let device: &str = device.as_ref();
+    let mut cmd = Command::new("resize2fs");
+    cmd.arg(device);
+    // Without a size, fill the whole device
+    if let Some(size_mb) = size_mb {
+        cmd.arg(format!("{}M", size_mb));
+    }
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| Error::UserSpaceResizingFailed {
+            reason: "Unable to get exit status",
+        })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::UserSpaceResizingFailed {
+            reason: "resize2fs error",
+        }
+        .with_output(&cmd, &output))
+    }
+}
+
+/// Grows the mounted filesystem on `device` to fill it.
+pub fn invoke_grow_filesystem<D>(
+    runner: &dyn Runner,
+    filesystem: Filesystem,
+    device: &D,
+    mount_point: &str,
+) -> Result<(), Error>
+where
+    D: AsRef<str>,
+{
+    let mut cmd = match filesystem {
+        Filesystem::Ext4 => return invoke_resize_filesystem(runner, device, None),
+        // XFS and btrfs grow through their mount point
+        Filesystem::Xfs => Command::new("xfs_growfs"),
+        Filesystem::Btrfs => {
+            let mut cmd = Command::new("btrfs");
+            cmd.args(["filesystem", "resize", "max"]);
+            cmd
+        }
+    };
+    cmd.arg(mount_point);
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| Error::UserSpaceResizingFailed {
+            reason: "Unable to get exit status",
+        })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::UserSpaceResizingFailed {
+            reason: "Unable to grow filesystem",
+        }
+        .with_output(&cmd, &output))
+    }
+}
+
+/// Forces a full `e2fsck` run, repairing what is safe to repair
+/// automatically, or only checking if `repair` is false.
+pub fn invoke_check_filesystem<P>(runner: &dyn Runner, path: &P, repair: bool) -> Result<(), Error>
+where
+    P: AsRef<str>,
+{
+    let path: &str = path.as_ref();
+    let mut cmd = Command::new("e2fsck");
+    cmd.arg("-f"); // check even if the filesystem seems clean
+    cmd.arg(if repair { "-p" } else { "-n" }); // never ask questions
+    cmd.arg(path);
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| Error::UserSpaceResizingFailed {
+            reason: "Unable to get exit status",
+        })?;
+    let error = match output.status.code() {
+        Some(0) => return Ok(()),
+        Some(1) if repair => return Ok(()),
+        Some(1) | Some(4) => Error::UserSpaceResizingFailed {
+            reason: "Filesystem has errors",
+        },
+        Some(8) => Error::UserSpaceResizingFailed {
+            reason: "e2fsck operational error",
+        },
+        None => Error::UserSpaceResizingFailed {
+            reason: "Process terminated by signal",
+        },
+        _ => Error::UserSpaceResizingFailed {
+            reason: "e2fsck error",
+        },
+    };
+    Err(error.with_output(&cmd, &output))
+}
+
+fn invoke_copy_user_space<P, B>(
+    runner: &dyn Runner,
+    path: &P,
+    backup_path: &B,
+    allocation: Allocation,
+) -> Result<(), Error>
+where
+    P: AsRef<str>,
+    B: AsRef<str>,
+{
+    let path: &str = path.as_ref();
+    let backup_path: &str = backup_path.as_ref();
+    let mut cmd = Command::new("cp");
+    cmd.arg(match allocation {
+        Allocation::Preallocated => "--sparse=never",
+        Allocation::Sparse => "--sparse=always",
+    });
+    cmd.arg("--preserve=mode,ownership");
+    cmd.arg(path);
+    cmd.arg(backup_path);
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| Error::UserSpaceResizingFailed {
+            reason: "Unable to get exit status",
+        })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::UserSpaceResizingFailed {
+            reason: "Unable to back up image",
+        }
+        .with_output(&cmd, &output))
+    }
+}
+
+fn invoke_restore_user_space<B, P>(
+    runner: &dyn Runner,
+    backup_path: &B,
+    path: &P,
+) -> Result<(), Error>
+where
+    B: AsRef<str>,
+    P: AsRef<str>,
+{
+    let backup_path: &str = backup_path.as_ref();
+    let path: &str = path.as_ref();
+    let mut cmd = Command::new("mv");
+    cmd.arg("--force");
+    cmd.arg(backup_path);
+    cmd.arg(path);
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| Error::UserSpaceResizingFailed {
+            reason: "Unable to get exit status",
+        })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::UserSpaceResizingFailed {
+            reason: "Unable to restore image",
+        }
+        .with_output(&cmd, &output))
+    }
+}
+
+fn invoke_find_loop_devices<P>(runner: &dyn Runner, path: &P) -> Result<Vec<String>, Error>
+where
+    P: AsRef<str>,
+{
+    let path: &str = path.as_ref();
+    let mut cmd = Command::new("losetup");
+    cmd.arg("--associated"); // list loop devices backed by the file
+    cmd.arg(path);
+    let output: Output = runner
+        .output(&mut cmd)
+        .map_err(|_| Error::UserSpaceUnmountingFailed {
+            reason: "Unable to get exit status",
+        })?;
+    if !output.status.success() {
+        return Err(Error::UserSpaceUnmountingFailed {
+            reason: "losetup error",
+        }
+        .with_output(&cmd, &output));
+    }
+    // Lines look like `/dev/loop0: [2049]:1234 (/home/user/volume)`
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.split(':').next())
+        .filter(|device| !device.is_empty())
+        .map(|device| device.to_string())
+        .collect())
+}
+
+fn invoke_archive_user_space<P, A>(
+    runner: &dyn Runner,
+    path: &P,
+    archive_path: &A,
+) -> Result<(), Error>
+where
+    P: AsRef<str>,
+    A: AsRef<str>,
+{
+    let path: &str = path.as_ref();
+    let archive_path: &str = archive_path.as_ref();
+    let mut cmd = Command::new("mv");
+    cmd.arg("--no-clobber");
+    cmd.arg(path);
+    cmd.arg(archive_path);
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| Error::UserSpaceDeletionFailed {
+            reason: "Unable to get exit status",
+        })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::UserSpaceDeletionFailed {
+            reason: "Unable to archive image",
+        }
+        .with_output(&cmd, &output))
+    }
+}
+
+pub fn invoke_unmount_user_space<P>(runner: &dyn Runner, mount_point: &P) -> Result<(), Error>
+where
+    P: AsRef<str>,
+{
+    let mount_point: &str = mount_point.as_ref();
+    let mut cmd = Command::new("umount");
+    cmd.arg(mount_point);
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| Error::UserSpaceUnmountingFailed {
+            reason: "Unable to get exit status",
+        })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::UserSpaceUnmountingFailed {
+            reason: "umount error",
+        }
+        .with_output(&cmd, &output))
+    }
+}
+
+fn invoke_detach_user_space<D>(runner: &dyn Runner, device: &D) -> Result<(), Error>
+where
+    D: AsRef<str>,
+{
+    let device: &str = device.as_ref();
+    let mut cmd = Command::new("losetup");
+    cmd.arg("--detach");
+    cmd.arg(device);
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| Error::UserSpaceUnmountingFailed {
+            reason: "Unable to get exit status",
+        })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::UserSpaceUnmountingFailed {
+            reason: "losetup error",
+        }
+        .with_output(&cmd, &output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RecordingRunner;
+    use std::env;
+    use std::process;
+
+    const ASSOCIATED: &str = "/dev/loop2: [2049]:12 (/home/bob/volume)\n";
+
+    fn user() -> User {
+        User {
+            username: "bob".to_string(),
+            base_directory: "/home".to_string(),
+            home_directory: "/home/bob".to_string(),
+        }
+    }
+
+    #[test]
+    fn create_user_space_creates_formats_and_mounts_image() {
+        let runner = RecordingRunner::new().respond("losetup --find", 0, "/dev/loop4\n");
+        let mut tx = Transaction::new();
+
+        let userspace = create_user_space(
+            &runner,
+            &user(),
+            16,
+            Allocation::Sparse,
+            &FormatOptions::default(),
+            "/srv/provme/images",
+            "/mnt",
+            "",
+            &mut tx,
+        )
+        .unwrap();
+
+        assert_eq!(userspace.path, "/srv/provme/images/bob.img");
+        assert_eq!(userspace.loop_device.as_deref(), Some("/dev/loop4"));
+        assert_eq!(userspace.mount_point, "/mnt/bob");
+        assert_eq!(
+            runner.actions(),
+            vec![
+                "mkdir --parents /srv/provme/images",
+                "chmod 0700 /srv/provme/images",
+                "truncate --size 16777216 /srv/provme/images/bob.img",
+                "mkfs.ext4 /srv/provme/images/bob.img",
+                "losetup --find --show /srv/provme/images/bob.img",
+                "mkdir --parents /mnt/bob",
+                "mount --types ext4 /dev/loop4 /mnt/bob",
+            ]
+        );
+    }
+
+    #[test]
+    fn create_user_space_rolls_back_in_reverse_order() {
+        let runner = RecordingRunner::new().respond("losetup --find", 0, "/dev/loop4\n");
+        let mut tx = Transaction::new();
+        create_user_space(
+            &runner,
+            &user(),
+            16,
+            Allocation::Preallocated,
+            &FormatOptions::default(),
+            "/srv/provme/images",
+            "/mnt",
+            "",
+            &mut tx,
+        )
+        .unwrap();
+
+        tx.rollback(&runner, Error::UserJailCreationFailed { reason: "test" });
+
+        assert_eq!(
+            runner.actions()[7..],
+            [
+                "umount /mnt/bob",
+                "rmdir /mnt/bob",
+                "losetup --detach /dev/loop4",
+                "rm /srv/provme/images/bob.img",
+            ]
+        );
+    }
+
+    #[test]
+    fn create_user_space_requires_loop_device() {
+        let runner = RecordingRunner::new();
+        let mut tx = Transaction::new();
+
+        let result = create_user_space(
+            &runner,
+            &user(),
+            16,
+            Allocation::Sparse,
+            &FormatOptions::default(),
+            "/srv/provme/images",
+            "/mnt",
+            "",
+            &mut tx,
+        );
+
+        assert!(matches!(
+            result,
+            Err(Error::UserSpaceMountingFailed {
+                reason: "losetup did not report a loop device"
+            })
+        ));
+    }
+
+    #[test]
+    fn create_user_space_reports_mkfs_failure() {
+        let runner =
+            RecordingRunner::new().fail("mkfs.ext4", 1, "mkfs.ext4: No space left on device\n");
+        let mut tx = Transaction::new();
+
+        let result = create_user_space(
+            &runner,
+            &user(),
+            16,
+            Allocation::Sparse,
+            &FormatOptions::default(),
+            "/srv/provme/images",
+            "/mnt",
+            "",
+            &mut tx,
+        );
+
+        match result {
+            Err(Error::CommandFailed {
+                error,
+                command,
+                exit_code,
+                stderr,
+            }) => {
+                assert!(matches!(
+                    *error,
+                    Error::UserSpaceFormattingFailed {
+                        reason: "mkfs.ext4 error"
+                    }
+                ));
+                assert_eq!(command, "mkfs.ext4 /srv/provme/images/bob.img");
+                assert_eq!(exit_code, Some(1));
+                assert_eq!(stderr, "mkfs.ext4: No space left on device");
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(runner.actions().len(), 4);
+    }
+
+    #[test]
+    fn format_options_tune_mkfs() {
+        let runner = RecordingRunner::new();
+        let format = FormatOptions {
+            reserved_percent: Some(0),
+            inode_ratio: Some(4096),
+            label: Some("bob".to_string()),
+            features: Some("^has_journal".to_string()),
+            ..FormatOptions::default()
+        };
+
+        invoke_format_user_space(&runner, &"/srv/provme/images/bob.img", &format).unwrap();
+
+        assert_eq!(
+            runner.actions(),
+            vec!["mkfs.ext4 -m 0 -i 4096 -L bob -O ^has_journal /srv/provme/images/bob.img"]
+        );
+    }
+
+    #[test]
+    fn create_user_space_keeps_existing_mount() {
+        let image_store = env::temp_dir().join(format!("provme-mounted-{}", process::id()));
+        fs::create_dir_all(&image_store).unwrap();
+        let image_store = image_store.to_str().unwrap();
+        let path = image_path(image_store, "bob");
+        let mut contents = vec![0; 16 * 1024 * 1024];
+        let offset = 1024 + 0x38; // magic number of the ext4 superblock
+        contents[offset..offset + 2].copy_from_slice(&[0x53, 0xef]);
+        fs::write(&path, contents).unwrap();
+        let runner = RecordingRunner::new().respond(
+            "losetup --associated",
+            0,
+            &format!("/dev/loop2: [2049]:12 ({})\n", path),
+        );
+        let mut tx = Transaction::new();
+        let mounts = "/dev/loop2 /mnt/bob ext4 rw,relatime 0 0\n";
+
+        let result = create_user_space(
+            &runner,
+            &user(),
+            16,
+            Allocation::Sparse,
+            &FormatOptions::default(),
+            image_store,
+            "/mnt",
+            mounts,
+            &mut tx,
+        );
+        fs::remove_dir_all(image_store).unwrap();
+
+        assert_eq!(result.unwrap().loop_device.as_deref(), Some("/dev/loop2"));
+        assert_eq!(
+            runner.actions(),
+            vec![format!("losetup --associated {}", path)]
+        );
+    }
+
+    #[test]
+    fn create_user_space_refuses_foreign_mount() {
+        let runner = RecordingRunner::new();
+        let mut tx = Transaction::new();
+        let mounts = "/dev/loop2 /mnt/bob ext4 rw,relatime 0 0\n";
+
+        let result = create_user_space(
+            &runner,
+            &user(),
+            16,
+            Allocation::Sparse,
+            &FormatOptions::default(),
+            "/srv/provme/images",
+            "/mnt",
+            mounts,
+            &mut tx,
+        );
+
+        assert!(matches!(
+            result,
+            Err(Error::UserSpaceMountingFailed {
+                reason: "Mount point is in use by another device"
+            })
+        ));
+        assert!(runner.actions().is_empty());
+    }
+
+    #[test]
+    fn create_user_space_completes_formatted_image() {
+        let image_store = env::temp_dir().join(format!("provme-userspace-{}", process::id()));
+        fs::create_dir_all(&image_store).unwrap();
+        let image_store = image_store.to_str().unwrap();
+        let path = image_path(image_store, "bob");
+        let mut contents = vec![0; 16 * 1024 * 1024];
+        let offset = 1024 + 0x38; // magic number of the ext4 superblock
+        contents[offset..offset + 2].copy_from_slice(&[0x53, 0xef]);
+        fs::write(&path, contents).unwrap();
+        let runner = RecordingRunner::new().respond(
+            "losetup --associated",
+            0,
+            &format!("/dev/loop7: [2049]:12 ({})\n", path),
+        );
+        let mut tx = Transaction::new();
+
+        let result = create_user_space(
+            &runner,
+            &user(),
+            16,
+            Allocation::Sparse,
+            &FormatOptions::default(),
+            image_store,
+            "/mnt",
+            "",
+            &mut tx,
+        );
+        fs::remove_dir_all(image_store).unwrap();
+
+        assert_eq!(result.unwrap().loop_device.as_deref(), Some("/dev/loop7"));
+        assert_eq!(
+            runner.actions(),
+            vec![
+                format!("losetup --associated {}", path),
+                "mkdir --parents /mnt/bob".to_string(),
+                "mount --types ext4 /dev/loop7 /mnt/bob".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn move_user_space_remounts_image_from_store() {
+        let runner = RecordingRunner::new()
+            .respond("losetup --find", 0, "/dev/loop3\n")
+            .respond("losetup --associated", 0, ASSOCIATED);
+        let mounts = "/dev/loop2 /mnt/bob ext4 rw,relatime 0 0\n";
+        let mut tx = Transaction::new();
+
+        move_user_space(
+            &runner,
+            "/home/bob/volume",
+            "/srv/provme/images/bob.img",
+            "/srv/provme/images",
+            Filesystem::Ext4,
+            "/mnt/bob",
+            mounts,
+            &mut tx,
+        )
+        .unwrap();
+
+        assert_eq!(
+            runner.actions(),
+            vec![
+                "umount /mnt/bob",
+                "losetup --associated /home/bob/volume",
+                "losetup --detach /dev/loop2",
+                "mkdir --parents /srv/provme/images",
+                "chmod 0700 /srv/provme/images",
+                "mv --no-clobber /home/bob/volume /srv/provme/images/bob.img",
+                "chmod 0600 /srv/provme/images/bob.img",
+                "losetup --find --show /srv/provme/images/bob.img",
+                "mount --types ext4 /dev/loop3 /mnt/bob",
+            ]
+        );
+    }
+
+    #[test]
+    fn move_user_space_moves_image_back_on_failure() {
+        let runner = RecordingRunner::new()
+            .respond("losetup --find", 0, "/dev/loop3\n")
+            .respond("losetup --associated", 0, ASSOCIATED)
+            .respond("mount", 32, "");
+        let mounts = "/dev/loop2 /mnt/bob ext4 rw,relatime 0 0\n";
+        let mut tx = Transaction::new();
+
+        let err = move_user_space(
+            &runner,
+            "/home/bob/volume",
+            "/srv/provme/images/bob.img",
+            "/srv/provme/images",
+            Filesystem::Ext4,
+            "/mnt/bob",
+            mounts,
+            &mut tx,
+        )
+        .unwrap_err();
+        tx.rollback(&runner, err);
+
+        assert_eq!(
+            runner.actions()[9..12],
+            [
+                "losetup --detach /dev/loop3",
+                "mv --no-clobber /srv/provme/images/bob.img /home/bob/volume",
+                "losetup --find --show /home/bob/volume",
+            ]
+        );
+    }
+
+    #[test]
+    fn create_user_space_rejects_overflowing_quota() {
+        let runner = RecordingRunner::new();
+        let mut tx = Transaction::new();
+
+        let result = create_user_space(
+            &runner,
+            &user(),
+            u64::MAX,
+            Allocation::Sparse,
+            &FormatOptions::default(),
+            "/srv/provme/images",
+            "/mnt",
+            "",
+            &mut tx,
+        );
+
+        assert!(matches!(
+            result,
+            Err(Error::UserSpaceCreationFailed {
+                reason: "Quota too large"
+            })
+        ));
+        assert!(runner.actions().is_empty());
+    }
+}
@@ -0,0 +1,223 @@
+use crate::{Error, Layout};
+
+/// Longest username useradd accepts.
+const MAX_USERNAME_LEN: usize = 32;
+
+/// Names of system accounts and mailbox conventions that must never become
+/// web space accounts.
+const RESERVED_USERNAMES: &[&str] = &[
+    "_apt",
+    "abuse",
+    "admin",
+    "administrator",
+    "backup",
+    "bin",
+    "daemon",
+    "ftp",
+    "games",
+    "gnats",
+    "hostmaster",
+    "irc",
+    "list",
+    "lp",
+    "mail",
+    "man",
+    "messagebus",
+    "news",
+    "nobody",
+    "nogroup",
+    "postmaster",
+    "proxy",
+    "root",
+    "sshd",
+    "sync",
+    "sys",
+    "syslog",
+    "uucp",
+    "webmaster",
+    "www-data",
+];
+
+/// Checks that `username` is a portable POSIX username: a lowercase letter
+/// or underscore followed by lowercase letters, digits, underscores and
+/// hyphens, at most 32 characters, and not a reserved name.
+pub fn validate_username(username: &str) -> Result<(), Error> {
+    let mut chars = username.chars();
+    match chars.next() {
+        None => {
+            return Err(Error::ValidationFailed {
+                reason: "Username is empty",
+            })
+        }
+        Some(first) if !(first.is_ascii_lowercase() || first == '_') => {
+            return Err(Error::ValidationFailed {
+                reason: "Username must start with a lowercase letter or `_`",
+            })
+        }
+        Some(_) => {}
+    }
+    if !chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-') {
+        return Err(Error::ValidationFailed {
+            reason: "Username may only contain lowercase letters, digits, `_` and `-`",
+        });
+    }
+    if username.len() > MAX_USERNAME_LEN {
+        return Err(Error::ValidationFailed {
+            reason: "Username longer than 32 characters",
+        });
+    }
+    if RESERVED_USERNAMES.contains(&username) || username.starts_with("systemd-") {
+        return Err(Error::ValidationFailed {
+            reason: "Username is reserved",
+        });
+    }
+    Ok(())
+}
+
+/// Checks that `path` is absolute and normalized, and free of characters
+/// that cannot be written into sshd configuration and systemd units.
+///
+/// Paths are joined with usernames as `<path>/<username>`, so a trailing or
+/// doubled `/` is refused: the kernel would report the joined path without
+/// it, and mounts would no longer be found in the mount table.
+pub fn validate_path(path: &str) -> Result<(), Error> {
+    if !path.starts_with('/') {
+        return Err(Error::ValidationFailed {
+            reason: "Path must be absolute",
+        });
+    }
+    if path[1..].split('/').any(str::is_empty) {
+        return Err(Error::ValidationFailed {
+            reason: "Path must not end with `/` or contain `//`",
+        });
+    }
+    if path
+        .split('/')
+        .any(|segment| segment == "." || segment == "..")
+    {
+        return Err(Error::ValidationFailed {
+            reason: "Path must not contain `.` or `..`",
+        });
+    }
+    if path.contains(|c: char| c.is_whitespace() || c.is_control()) {
+        return Err(Error::ValidationFailed {
+            reason: "Path must not contain whitespace or control characters",
+        });
+    }
+    Ok(())
+}
+
+/// Checks every directory of `layout` and the name of its volume group.
+pub fn validate_layout(layout: &Layout) -> Result<(), Error> {
+    validate_path(&layout.base_directory)?;
+    validate_path(&layout.mount_base)?;
+    validate_path(&layout.sshd_config_dir)?;
+    validate_path(&layout.inventory_path)?;
+    validate_path(&layout.image_store)?;
+    validate_path(&layout.authorized_keys_dir)?;
+    // LVM refuses other names, and the group becomes part of device paths
+    let volume_group = &layout.volume_group;
+    if volume_group.is_empty()
+        || volume_group.starts_with('-')
+        || !volume_group
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+_.-".contains(c))
+        || volume_group == "."
+        || volume_group == ".."
+    {
+        return Err(Error::ValidationFailed {
+            reason: "Invalid volume group name",
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn portable_usernames_are_accepted() {
+        for username in &["bob", "_bob", "web-bob", "b0b_1", &"a".repeat(32)] {
+            assert!(validate_username(username).is_ok(), "{}", username);
+        }
+    }
+
+    #[test]
+    fn unsafe_usernames_are_rejected() {
+        for username in &[
+            "",
+            "../bob",
+            "bob/x",
+            "Bob",
+            "-bob",
+            "1bob",
+            "bob$",
+            "bob smith",
+            "böb",
+            &"a".repeat(33),
+        ] {
+            assert!(validate_username(username).is_err(), "{:?}", username);
+        }
+    }
+
+    #[test]
+    fn reserved_usernames_are_rejected() {
+        for username in &["root", "www-data", "systemd-network"] {
+            assert!(matches!(
+                validate_username(username),
+                Err(Error::ValidationFailed {
+                    reason: "Username is reserved"
+                })
+            ));
+        }
+    }
+
+    #[test]
+    fn paths_must_be_absolute_and_normalized() {
+        assert!(validate_path("/srv/home").is_ok());
+        assert!(validate_path("srv/home").is_err());
+        assert!(validate_path("/srv/../etc").is_err());
+        assert!(validate_path("/srv/./home").is_err());
+        assert!(validate_path("/srv/my home").is_err());
+        assert!(validate_path("/srv/home\n").is_err());
+    }
+
+    #[test]
+    fn paths_must_not_have_empty_segments() {
+        for path in &["/", "/mnt/", "/mnt//web", "//mnt"] {
+            assert!(
+                matches!(
+                    validate_path(path),
+                    Err(Error::ValidationFailed {
+                        reason: "Path must not end with `/` or contain `//`"
+                    })
+                ),
+                "{:?}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn layouts_are_checked_completely() {
+        assert!(validate_layout(&Layout::default()).is_ok());
+
+        let layout = Layout {
+            image_store: "images".to_string(),
+            ..Layout::default()
+        };
+        assert!(validate_layout(&layout).is_err());
+
+        let layout = Layout {
+            volume_group: "-vg".to_string(),
+            ..Layout::default()
+        };
+        assert!(matches!(
+            validate_layout(&layout),
+            Err(Error::ValidationFailed {
+                reason: "Invalid volume group name"
+            })
+        ));
+    }
+}
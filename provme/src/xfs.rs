@@ -0,0 +1,348 @@
+use crate::{
+    containing_mount, jail_home_directory, release_home_directory, user_id, Allocation, Backend,
+    Error, Runner, Transaction, User, UserSpace, BYTES_PER_INODE,
+};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// Limits the home directory of `user` with an XFS project quota, using the
+/// user ID from `passwd` as project ID. The home directory becomes the
+/// chroot directory.
+pub fn create_xfs_space(
+    runner: &dyn Runner,
+    user: &User,
+    quota_mb: u64,
+    passwd: &str,
+    mounts: &str,
+    tx: &mut Transaction,
+) -> Result<UserSpace, Error> {
+    // Prepare arguments
+    let home_directory = &user.home_directory;
+    let mount = xfs_mount(mounts, home_directory).ok_or(Error::UserSpaceCreationFailed {
+        reason: "The home directory is not on XFS",
+    })?;
+    let project_id = project_id(passwd, &user.username, |reason| {
+        Error::UserSpaceCreationFailed { reason }
+    })?;
+
+    // Hand the home directory to root
+    jail_home_directory(runner, home_directory)?;
+    let undo_username = user.username.clone();
+    let undo_home_directory = home_directory.clone();
+    tx.on_rollback(format!("release {}", home_directory), move |runner| {
+        release_home_directory(runner, &undo_username, &undo_home_directory)
+    });
+
+    // Assign the home directory to the project
+    invoke_xfs_quota(
+        runner,
+        &format!("project -s -p {} {}", home_directory, project_id),
+        &mount,
+        |reason| Error::UserSpaceCreationFailed { reason },
+    )?;
+    let undo_home_directory = home_directory.clone();
+    let undo_project_id = project_id.clone();
+    let undo_mount = mount.clone();
+    tx.on_rollback(format!("clear project {}", project_id), move |runner| {
+        invoke_xfs_quota(
+            runner,
+            &format!("project -C -p {} {}", undo_home_directory, undo_project_id),
+            &undo_mount,
+            |reason| Error::UserSpaceCreationFailed { reason },
+        )
+    });
+
+    // Set block and inode limits
+    let limit = limit_command(&project_id, quota_mb, |reason| {
+        Error::UserSpaceCreationFailed { reason }
+    })?;
+    invoke_xfs_quota(runner, &limit, &mount, |reason| {
+        Error::UserSpaceCreationFailed { reason }
+    })?;
+    let undo_project_id = project_id.clone();
+    let undo_mount = mount.clone();
+    tx.on_rollback(
+        format!("clear quota of project {}", project_id),
+        move |runner| {
+            let limit = limit_command(&undo_project_id, 0, |reason| {
+                Error::UserSpaceCreationFailed { reason }
+            })?;
+            invoke_xfs_quota(runner, &limit, &undo_mount, |reason| {
+                Error::UserSpaceCreationFailed { reason }
+            })
+        },
+    );
+
+    // Log
+    println!(
+        "Project quota set: {size}M (project {project} on {target})",
+        size = quota_mb,
+        project = project_id,
+        target = mount,
+    );
+
+    // Instantiate data structure
+    Ok(UserSpace {
+        name: "home".to_string(),
+        path: home_directory.clone(),
+        size_mb: quota_mb,
+        allocation: Allocation::Sparse,
+        filesystem: "xfs".to_string(),
+        format: None,
+        backend: Backend::Xfs,
+        loop_device: None,
+        mount_point: home_directory.clone(),
+    })
+}
+
+/// Changes the limits of an XFS project quota account.
+pub fn resize_xfs_space(
+    runner: &dyn Runner,
+    username: &str,
+    home_directory: &str,
+    quota_mb: u64,
+    passwd: &str,
+    mounts: &str,
+) -> Result<(), Error> {
+    // Prepare arguments
+    let mount = xfs_mount(mounts, home_directory).ok_or(Error::UserSpaceResizingFailed {
+        reason: "The home directory is not on XFS",
+    })?;
+    let project_id = project_id(passwd, username, |reason| Error::UserSpaceResizingFailed {
+        reason,
+    })?;
+
+    // Set block and inode limits
+    let limit = limit_command(&project_id, quota_mb, |reason| {
+        Error::UserSpaceResizingFailed { reason }
+    })?;
+    invoke_xfs_quota(runner, &limit, &mount, |reason| {
+        Error::UserSpaceResizingFailed { reason }
+    })?;
+
+    // Log
+    println!(
+        "Project quota set: {size}M (project {project} on {target})",
+        size = quota_mb,
+        project = project_id,
+        target = mount,
+    );
+
+    Ok(())
+}
+
+/// Clears the limits and project of an XFS project quota account and
+/// returns its home directory to the user, so `userdel --remove` deletes it.
+pub fn delete_xfs_space(
+    runner: &dyn Runner,
+    username: &str,
+    home_directory: &str,
+) -> Result<(), Error> {
+    // Prepare arguments
+    if !Path::new(home_directory).is_dir() {
+        return Ok(());
+    }
+    let mounts =
+        fs::read_to_string("/proc/self/mounts").map_err(|_| Error::UserSpaceDeletionFailed {
+            reason: "Unable to read mount table",
+        })?;
+    let mount = xfs_mount(&mounts, home_directory).ok_or(Error::UserSpaceDeletionFailed {
+        reason: "The home directory is not on XFS",
+    })?;
+    let passwd = fs::read_to_string("/etc/passwd").map_err(|_| Error::UserSpaceDeletionFailed {
+        reason: "Unable to read passwd database",
+    })?;
+    let project_id = project_id(&passwd, username, |reason| Error::UserSpaceDeletionFailed {
+        reason,
+    })?;
+
+    // Clear limits and project
+    let limit = limit_command(&project_id, 0, |reason| Error::UserSpaceDeletionFailed {
+        reason,
+    })?;
+    invoke_xfs_quota(runner, &limit, &mount, |reason| {
+        Error::UserSpaceDeletionFailed { reason }
+    })?;
+    invoke_xfs_quota(
+        runner,
+        &format!("project -C -p {} {}", home_directory, project_id),
+        &mount,
+        |reason| Error::UserSpaceDeletionFailed { reason },
+    )?;
+
+    // Return home directory to the user
+    release_home_directory(runner, username, home_directory)?;
+
+    // Log
+    println!(
+        "Project quota cleared: project {project} ({target})",
+        project = project_id,
+        target = mount
+    );
+
+    Ok(())
+}
+
+/// Finds the target of the XFS filesystem holding `path`.
+fn xfs_mount(mounts: &str, path: &str) -> Option<String> {
+    containing_mount(mounts, path)
+        .filter(|mount| mount.filesystem == "xfs")
+        .map(|mount| mount.target)
+}
+
+/// Renders the `xfs_quota` command setting the hard block and inode limits
+/// of a project. Zero clears the limits.
+fn limit_command(
+    project_id: &str,
+    quota_mb: u64,
+    failed: fn(&'static str) -> Error,
+) -> Result<String, Error> {
+    let size = quota_mb
+        .checked_mul(1024 * 1024)
+        .ok_or_else(|| failed("Quota too large"))?;
+    Ok(format!(
+        "limit -p bhard={size}m ihard={inodes} {project}",
+        size = quota_mb,
+        inodes = size / BYTES_PER_INODE,
+        project = project_id,
+    ))
+}
+
+/// Finds the project ID of `username`, which is its user ID in `passwd`.
+fn project_id(
+    passwd: &str,
+    username: &str,
+    failed: fn(&'static str) -> Error,
+) -> Result<String, Error> {
+    user_id(passwd, username)
+        .map(|uid| uid.to_string())
+        .ok_or_else(|| failed("User not found in passwd database"))
+}
+
+fn invoke_xfs_quota(
+    runner: &dyn Runner,
+    command: &str,
+    target: &str,
+    failed: fn(&'static str) -> Error,
+) -> Result<(), Error> {
+    let mut cmd = Command::new("xfs_quota");
+    cmd.arg("-x"); // enable commands that modify quotas
+    cmd.args(["-c", command]);
+    cmd.arg(target);
+    let output = runner
+        .output(&mut cmd)
+        .map_err(|_| failed("Unable to get exit status"))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(failed("xfs_quota error").with_output(&cmd, &output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RecordingRunner;
+
+    const MOUNTS: &str = "/dev/sda1 / ext4 rw,relatime 0 0\n\
+                          /dev/sdb1 /home xfs rw,relatime,prjquota 0 0\n";
+    const PASSWD: &str = "bob:x:1001:1001:mkwebuser bob:/home/bob:/usr/sbin/nologin\n";
+
+    fn user() -> User {
+        User {
+            username: "bob".to_string(),
+            base_directory: "/home".to_string(),
+            home_directory: "/home/bob".to_string(),
+        }
+    }
+
+    #[test]
+    fn create_xfs_space_assigns_project_and_limits() {
+        let runner = RecordingRunner::new();
+        let mut tx = Transaction::new();
+
+        let userspace = create_xfs_space(&runner, &user(), 1024, PASSWD, MOUNTS, &mut tx).unwrap();
+
+        assert_eq!(userspace.backend, Backend::Xfs);
+        assert_eq!(userspace.mount_point, "/home/bob");
+        assert_eq!(
+            runner.actions(),
+            vec![
+                "chown root:root /home/bob",
+                "chmod 0755 /home/bob",
+                "xfs_quota -x -c 'project -s -p /home/bob 1001' /home",
+                "xfs_quota -x -c 'limit -p bhard=1024m ihard=65536 1001' /home",
+            ]
+        );
+    }
+
+    #[test]
+    fn create_xfs_space_rolls_back_limits_and_project() {
+        let runner = RecordingRunner::new();
+        let mut tx = Transaction::new();
+        create_xfs_space(&runner, &user(), 1024, PASSWD, MOUNTS, &mut tx).unwrap();
+
+        tx.rollback(&runner, Error::UserJailCreationFailed { reason: "test" });
+
+        assert_eq!(
+            runner.actions()[4..],
+            [
+                "xfs_quota -x -c 'limit -p bhard=0m ihard=0 1001' /home",
+                "xfs_quota -x -c 'project -C -p /home/bob 1001' /home",
+                "chown bob:bob /home/bob",
+            ]
+        );
+    }
+
+    #[test]
+    fn create_xfs_space_requires_xfs() {
+        let runner = RecordingRunner::new();
+        let mut tx = Transaction::new();
+
+        let result = create_xfs_space(
+            &runner,
+            &user(),
+            1024,
+            PASSWD,
+            "/dev/sdb1 /home ext4 rw 0 0\n",
+            &mut tx,
+        );
+
+        assert!(matches!(
+            result,
+            Err(Error::UserSpaceCreationFailed {
+                reason: "The home directory is not on XFS"
+            })
+        ));
+        assert!(runner.actions().is_empty());
+    }
+
+    #[test]
+    fn create_xfs_space_requires_user_id() {
+        let runner = RecordingRunner::new();
+        let mut tx = Transaction::new();
+
+        let result = create_xfs_space(&runner, &user(), 1024, "", MOUNTS, &mut tx);
+
+        assert!(matches!(
+            result,
+            Err(Error::UserSpaceCreationFailed {
+                reason: "User not found in passwd database"
+            })
+        ));
+        assert!(runner.actions().is_empty());
+    }
+
+    #[test]
+    fn resize_xfs_space_sets_new_limits() {
+        let runner = RecordingRunner::new();
+
+        resize_xfs_space(&runner, "bob", "/home/bob", 2048, PASSWD, MOUNTS).unwrap();
+
+        assert_eq!(
+            runner.actions()[0],
+            "xfs_quota -x -c 'limit -p bhard=2048m ihard=131072 1001' /home"
+        );
+    }
+}